@@ -0,0 +1,291 @@
+//! Derive macros that write the packet codec boilerplate for `src/packets.rs`: packets, packet sets, records and enums.
+//!
+//! The generated code refers to `crate::packets::*`, so these are only usable from within the server crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields, LitInt, Type};
+
+/// Implements `Packet` (id and field layout), `Encode` and `Decode` for a packet struct.
+///
+/// ```ignore
+/// #[derive(Packet)]
+/// #[packet(id = 3)]
+/// pub struct PktS2C_Foo{ pub count: u32, #[packet(exhaustive)] pub text: String }
+/// ```
+/// Fields are written in declaration order using their `Wire` implementation.
+/// `#[packet(exhaustive)]` marks a trailing `String` that reads to the end of the packet.
+/// An `Option` field is absent once the packet runs out, so it also has to be last.
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_packet(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implements `Wire` for a struct, so it can be used as a packet field (e.g. in a `Vec`).
+///
+/// ```ignore
+/// #[derive(Record)]
+/// pub struct Foo{ pub id: u32, pub name: String }
+/// ```
+/// Fields are written in declaration order. Exhaustive and `Option` fields are not allowed.
+#[proc_macro_derive(Record)]
+pub fn derive_record(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_record(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implements `Wire` for a fieldless `#[repr(u8)]` enum, which travels as its discriminant.
+///
+/// ```ignore
+/// #[repr(u8)]
+/// #[derive(Clone, Copy, WireEnum)]
+/// pub enum Foo{ A = 0, B = 1 }
+/// ```
+/// Unknown discriminants fail to decode.
+#[proc_macro_derive(WireEnum)]
+pub fn derive_wire_enum(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_wire_enum(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implements the packet id enum, `Encode`, the dispatching `Decode` and `schema()` for an enum of packets.
+///
+/// Each variant must wrap exactly one `#[derive(Packet)]` struct.
+/// For `enum PktC2S` the id enum is named `PktC2Sid`, its discriminants taken from each packet's `#[packet(id)]`.
+#[proc_macro_derive(PacketSet)]
+pub fn derive_packet_set(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_packet_set(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn expand_packet(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+
+    // #[packet(id = N)]
+    let mut id: Option<LitInt> = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                id = Some(meta.value()?.parse()?);
+                return Ok(());
+            }
+            Err(meta.error("expected `id = <u8>`"))
+        })?;
+    }
+    let Some(id) = id else {
+        return Err(Error::new(name.span(), "packets need an id: #[packet(id = N)]"));
+    };
+    id.base10_parse::<u8>()?;
+
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(name.span(), "Packet can only be derived for structs"));
+    };
+    let fields = match &data.fields {
+        Fields::Named(x) => x.named.iter().collect(),
+        Fields::Unit => vec![],
+        Fields::Unnamed(x) => return Err(Error::new(x.span(), "packet fields must be named")),
+    };
+
+    let mut puts = vec![];
+    let mut gets = vec![];
+    let mut layout = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let mut exhaustive = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("exhaustive") {
+                    exhaustive = true;
+                    return Ok(());
+                }
+                Err(meta.error("expected `exhaustive`"))
+            })?;
+        }
+        if exhaustive && i != fields.len() - 1 {
+            return Err(Error::new(field.span(), "only the last field can be exhaustive"));
+        }
+        if is_option(&field.ty) && i != fields.len() - 1 {
+            return Err(Error::new(field.span(), "only the last field can be an Option, as it's absent once the packet runs out"));
+        }
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let fieldname = ident.to_string();
+        if exhaustive {
+            layout.push(quote!{ (#fieldname, crate::packets::WireType::ExhaustiveStr), });
+            puts.push(quote!{ enc.append_exhaustive_str(&self.#ident); });
+            gets.push(quote!{ #ident: src.get_exhaustive_str(), });
+        } else {
+            layout.push(quote!{ (#fieldname, <#ty as crate::packets::Wire>::wire_type()), });
+            puts.push(quote!{ crate::packets::Wire::put(&self.#ident, &mut enc); });
+            gets.push(quote!{ #ident: crate::packets::Wire::get(src)?, });
+        }
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let construct = match &data.fields {
+        Fields::Unit => quote!{ Self },
+        _ => quote!{ Self{ #(#gets)* } },
+    };
+    Ok(quote!{
+        impl #impl_generics crate::packets::Packet for #name #ty_generics #where_clause {
+            const ID: u8 = #id;
+            fn fields() -> Vec<(&'static str, crate::packets::WireType)> {
+                vec![ #(#layout)* ]
+            }
+        }
+        impl #impl_generics crate::packets::Encode for #name #ty_generics #where_clause {
+            fn encode(self) -> Vec<u8> {
+                let mut enc = crate::packets::Encoder::new();
+                enc.append_u8(<Self as crate::packets::Packet>::ID);
+                #(#puts)*
+                return enc.consume();
+            }
+        }
+        impl #impl_generics crate::packets::Decode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn decode(src: &mut crate::packets::Decoder) -> Result<Self, ()> {
+                Ok(#construct)
+            }
+        }
+    })
+}
+
+// Whether a field reads to the end of the packet when it's absent
+fn is_option(ty: &Type) -> bool {
+    let Type::Path(path) = ty else { return false };
+    path.qself.is_none() && path.path.segments.last().is_some_and(|x| x.ident == "Option")
+}
+
+fn expand_record(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(name.span(), "Record can only be derived for structs"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new(name.span(), "record fields must be named"));
+    };
+
+    let mut puts = vec![];
+    let mut gets = vec![];
+    let mut layout = vec![];
+    for field in fields.named.iter() {
+        if is_option(&field.ty) {
+            return Err(Error::new(field.span(), "record fields can't be an Option, as a record may not be at the end of the packet"));
+        }
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let fieldname = ident.to_string();
+        layout.push(quote!{ (#fieldname, <#ty as crate::packets::Wire>::wire_type()), });
+        puts.push(quote!{ crate::packets::Wire::put(&self.#ident, enc); });
+        gets.push(quote!{ #ident: crate::packets::Wire::get(src)?, });
+    }
+
+    let recordname = name.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote!{
+        impl #impl_generics crate::packets::Wire for #name #ty_generics #where_clause {
+            fn put(&self, enc: &mut crate::packets::Encoder) {
+                #(#puts)*
+            }
+            fn get(src: &mut crate::packets::Decoder) -> Result<Self, ()> {
+                Ok(Self{ #(#gets)* })
+            }
+            fn wire_type() -> crate::packets::WireType {
+                crate::packets::WireType::Record(#recordname, vec![ #(#layout)* ])
+            }
+        }
+    })
+}
+
+fn expand_wire_enum(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new(name.span(), "WireEnum can only be derived for enums"));
+    };
+    let mut variants = vec![];
+    for variant in data.variants.iter() {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new(variant.span(), "WireEnum variants can't have fields"));
+        }
+        variants.push(&variant.ident);
+    }
+    let variantnames = variants.iter().map(|x| x.to_string());
+    let enumname = name.to_string();
+
+    Ok(quote!{
+        impl crate::packets::Wire for #name {
+            fn put(&self, enc: &mut crate::packets::Encoder) {
+                enc.append_u8(*self as u8);
+            }
+            fn get(src: &mut crate::packets::Decoder) -> Result<Self, ()> {
+                let x = src.get_u8()?;
+                #( if x == Self::#variants as u8 { return Ok(Self::#variants); } )*
+                return Err(());
+            }
+            fn wire_type() -> crate::packets::WireType {
+                crate::packets::WireType::Enum(#enumname, vec![ #( (#variantnames, Self::#variants as u8), )* ])
+            }
+        }
+    })
+}
+
+fn expand_packet_set(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let idname = format_ident!("{}id", name);
+
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new(name.span(), "PacketSet can only be derived for enums"));
+    };
+    let mut variants = vec![];
+    let mut packets = vec![];
+    for variant in data.variants.iter() {
+        let ty = match &variant.fields {
+            Fields::Unnamed(x) if x.unnamed.len() == 1 => &x.unnamed[0].ty,
+            _ => return Err(Error::new(variant.span(), "each variant must wrap a single packet struct")),
+        };
+        variants.push(&variant.ident);
+        packets.push(ty);
+    }
+    let variantnames = variants.iter().map(|x| x.to_string());
+
+    Ok(quote!{
+        #[repr(u8)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #vis enum #idname{
+            #( #variants = <#packets as crate::packets::Packet>::ID, )*
+        }
+        impl #idname{
+            pub fn from_u8(id: u8) -> Option<Self>{
+                #( if id == Self::#variants as u8 { return Some(Self::#variants); } )*
+                return None;
+            }
+        }
+        impl #name{
+            /// Layout of every packet in this direction, ordered by declaration.
+            pub fn schema() -> Vec<crate::packets::PacketSchema>{
+                vec![ #( crate::packets::PacketSchema{
+                    name: #variantnames,
+                    id: <#packets as crate::packets::Packet>::ID,
+                    fields: <#packets as crate::packets::Packet>::fields(),
+                }, )* ]
+            }
+        }
+        impl crate::packets::Encode for #name{
+            fn encode(self) -> Vec<u8> {
+                match self{
+                    #( Self::#variants(p) => p.encode(), )*
+                }
+            }
+        }
+        impl crate::packets::Decode for #name{
+            fn decode(src: &mut crate::packets::Decoder) -> Result<Self, ()> {
+                let Some(kind) = #idname::from_u8(src.get_u8()?) else { return Err(()) };
+                Ok(match kind{
+                    #( #idname::#variants => Self::#variants(<#packets as crate::packets::Decode>::decode(src)?), )*
+                })
+            }
+        }
+    })
+}
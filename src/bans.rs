@@ -0,0 +1,138 @@
+//! Bans, kept in the file at `bans_path` (`bans.json` in the working directory by default).
+//! Addresses are checked at `/connect` before a peer is made. Sessions and names are checked in Hello and SetName.
+
+use std::{fmt::Display, fs, net::IpAddr, sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{chatroom::ROOMS, config, names, usersession::{SessionId, UserSession}};
+
+lazy_static!{
+    static ref BANS: Mutex<Vec<Ban>> = Mutex::new(load());
+}
+
+/// Who a ban applies to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanTarget{
+    Session(SessionId),
+    /// Also covers names that look like it
+    Name(String),
+    Ip(IpAddr),
+}
+impl Display for BanTarget{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            BanTarget::Session(sid) => write!(f, "session {}", sid),
+            BanTarget::Name(name) => write!(f, "name {}", name),
+            BanTarget::Ip(ip) => write!(f, "ip {}", ip),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban{
+    pub target: BanTarget,
+    pub reason: String,
+    /// Unix milliseconds
+    pub since: u64,
+}
+impl Ban{
+    /// Whether the ban applies to the session
+    pub fn covers(&self, user: &UserSession)->bool{
+        match &self.target{
+            BanTarget::Session(sid) => *sid == user.id,
+            BanTarget::Name(name) => names::confusable(name, &user.username),
+            BanTarget::Ip(ip) => user.ip() == Some(ip.to_canonical()),
+        }
+    }
+}
+
+// Reads the bans from disk. A missing file is no bans.
+fn load()->Vec<Ban>{
+    let path = &config::get().bans_path;
+    let bans = match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            warn!("Could not read the bans in {} ({e}). Starting with none.", path.display());
+            vec![]
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => { warn!("Could not open {} ({e}). Starting with no bans.", path.display()); vec![] }
+    };
+    info!("Loaded {} bans from {}", bans.len(), path.display());
+    return bans;
+}
+
+// Writes the bans to disk. A failed write keeps them for this run only.
+fn save(bans: &[Ban]){
+    let path = &config::get().bans_path;
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let result = serde_json::to_vec_pretty(bans).map_err(std::io::Error::from)
+        .and_then(|x| fs::write(&tmp, x))
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = result { warn!("Could not save the bans to {} ({e})", path.display()); }
+}
+
+/// Bans `target`, then kicks everyone it covers. False if it was already banned.
+pub async fn ban(target: BanTarget, reason: String)->bool{
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0);
+    // An IPv4 client can show up as IPv6 (::ffff:a.b.c.d), so addresses are kept in their canonical form
+    let target = match target {
+        BanTarget::Ip(ip) => BanTarget::Ip(ip.to_canonical()),
+        x => x,
+    };
+    let ban = Ban{ target, reason, since };
+    {
+        let mut bans = BANS.lock().unwrap();
+        if bans.iter().any(|x| x.target == ban.target) { return false; }
+        bans.push(ban.clone());
+        save(&bans);
+    }
+    info!("Banned {} ({})", ban.target, ban.reason);
+    for room in ROOMS.all().await {
+        for (p, user) in room.members().await {
+            if ban.covers(&*user.read().await) {
+                room.kick(p.sid, format!("Banned: {}", ban.reason)).await;
+            }
+        }
+    }
+    return true;
+}
+
+/// Lifts the ban numbered `index` in `list()`
+pub fn unban(index: usize)->Option<Ban>{
+    let mut bans = BANS.lock().unwrap();
+    if index >= bans.len() { return None; }
+    let ban = bans.remove(index);
+    save(&bans);
+    info!("Unbanned {}", ban.target);
+    return Some(ban);
+}
+
+/// Every ban, oldest first
+pub fn list()->Vec<Ban>{
+    return BANS.lock().unwrap().clone();
+}
+
+/// The reason the address is banned, if it is
+pub fn ip_banned(ip: IpAddr)->Option<String>{
+    let ip = ip.to_canonical();
+    let bans = BANS.lock().unwrap();
+    // Bans saved before addresses were made canonical might not be
+    return bans.iter().find(|x| matches!(x.target, BanTarget::Ip(x) if x.to_canonical() == ip)).map(|x| x.reason.clone());
+}
+
+/// The reason the session is banned, if any ban covers it
+pub fn session_banned(user: &UserSession)->Option<String>{
+    let bans = BANS.lock().unwrap();
+    return bans.iter().find(|x| x.covers(user)).map(|x| x.reason.clone());
+}
+
+/// The reason the name is banned, if it or a lookalike is
+pub fn name_banned(name: &str)->Option<String>{
+    let bans = BANS.lock().unwrap();
+    return bans.iter().find(|x| matches!(&x.target, BanTarget::Name(n) if names::confusable(n, name))).map(|x| x.reason.clone());
+}
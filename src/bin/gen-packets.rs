@@ -0,0 +1,10 @@
+//! Regenerates the web client's packet module from `src/packets.rs`.
+use std::path::Path;
+
+use webrtc_native_receiver::packets::typescript;
+
+fn main(){
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(typescript::OUTPUT_PATH);
+    std::fs::write(&path, typescript::render()).expect("Could not write the packet module");
+    println!("Wrote {}", path.display());
+}
@@ -0,0 +1,156 @@
+//! Checks chat messages before they go out. Control characters are dropped, then size, rate, slow mode and repeats are limited.
+//! The limits come from the config. Slow mode can also be changed while running with the console's /slowmode.
+
+use std::{collections::VecDeque, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+
+use lazy_static::lazy_static;
+
+use crate::{config, fi, names, packets::NoticeKind};
+
+/// Recent messages of each session kept to spot repeats
+const REPEAT_HISTORY: usize = 5;
+
+lazy_static!{
+    // Seconds each session has to wait between messages. 0 is off.
+    static ref SLOW_MODE: AtomicU64 = AtomicU64::new(config::get().slow_mode);
+}
+
+/// Time each session has to wait between messages. Zero when slow mode is off.
+pub fn slow_mode()->Duration{
+    return Duration::from_secs(SLOW_MODE.load(Ordering::Relaxed));
+}
+pub fn set_slow_mode(interval: Duration){
+    SLOW_MODE.store(interval.as_secs(), Ordering::Relaxed);
+}
+
+/// Drops control and invisible formatting characters, and surrounding whitespace. Tabs become spaces.
+pub fn clean(text: &str)->String{
+    let kept: String = text.chars()
+        .map(|c| fi!(c == '\t', ' ', c))
+        .filter(|c| !c.is_control() && !names::invisible(*c))
+        .collect();
+    return kept.trim().to_owned();
+}
+
+/// Per-session limits on chatting
+pub struct ChatLimiter{
+    // Token bucket: sending takes one, they come back at `message_rate` per second up to `message_burst`
+    tokens: f64,
+    refilled: Instant,
+    last_sent: Option<Instant>,
+    // What was sent recently, lowercased, and when
+    recent: VecDeque<(String, Instant)>,
+    // Set while a command runs that's already been counted, so a message it sends doesn't take a second token
+    prepaid: bool,
+}
+impl ChatLimiter{
+    pub fn new()->Self{
+        Self{ tokens: config::get().message_burst as f64, refilled: Instant::now(), last_sent: None, recent: VecDeque::new(), prepaid: false }
+    }
+
+    /// Counts a chat command against the rate limit, before it runs. Whatever it sends until `command_done` doesn't take another token.
+    pub fn command(&mut self)->Result<(), (NoticeKind, String)>{
+        self.refill(Instant::now());
+        if self.tokens < 1.0 {
+            return Err((NoticeKind::RateLimited, "You're sending commands too quickly. Slow down.".into()));
+        }
+        self.tokens -= 1.0;
+        self.prepaid = true;
+        return Ok(());
+    }
+    pub fn command_done(&mut self){
+        self.prepaid = false;
+    }
+    // Gives back the tokens earned since the last refill
+    fn refill(&mut self, now: Instant){
+        let config = config::get();
+        self.tokens = (self.tokens + (now - self.refilled).as_secs_f64() * config.message_rate).min(config.message_burst as f64);
+        self.refilled = now;
+    }
+
+    /// Whether `text` (already cleaned) can be sent now. If so it's counted against the limits.
+    /// None for things that count as a message without text of their own, so only the rate limits apply.
+    /// `slow_exempt` skips slow mode, for staff.
+    pub fn check(&mut self, text: Option<&str>, slow_exempt: bool)->Result<(), (NoticeKind, String)>{
+        let config = config::get();
+        let now = Instant::now();
+        if let Some(text) = text.filter(|x| x.len() > config.max_message_bytes) {
+            return Err((NoticeKind::TooLong, format!("Messages can be at most {} bytes. That one was {}.", config.max_message_bytes, text.len())));
+        }
+        let slow = slow_mode();
+        if !slow_exempt && !slow.is_zero() {
+            if let Some(wait) = self.last_sent.map(|x| slow.saturating_sub(now - x)).filter(|x| !x.is_zero()) {
+                return Err((NoticeKind::SlowMode, format!("Slow mode is on. You can send another message in {} seconds.", wait.as_secs().max(1))));
+            }
+        }
+        self.refill(now);
+        if !self.prepaid && self.tokens < 1.0 {
+            return Err((NoticeKind::RateLimited, "You're sending messages too quickly. Slow down.".into()));
+        }
+        let window = Duration::from_secs(config.repeat_window);
+        self.recent.retain(|(_, at)| now - *at < window);
+        let lowered = text.map(str::to_lowercase);
+        if let Some(lowered) = &lowered {
+            if self.recent.iter().any(|(x, _)| x == lowered) {
+                return Err((NoticeKind::Repeated, "You just sent that.".into()));
+            }
+        }
+
+        if !std::mem::take(&mut self.prepaid) { self.tokens -= 1.0; }
+        self.last_sent = Some(now);
+        if let Some(lowered) = lowered.filter(|_| !window.is_zero()) {
+            if self.recent.len() == REPEAT_HISTORY { self.recent.pop_front(); }
+            self.recent.push_back((lowered, now));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    // With the default config: a burst of 5, refilling at 1 per second
+    #[test]
+    fn burst_runs_out(){
+        let mut limiter = ChatLimiter::new();
+        for i in 0..5 { assert!(limiter.check(Some(&format!("message {i}")), false).is_ok()); }
+        assert_eq!(limiter.check(Some("one more"), false).map_err(|x| x.0), Err(NoticeKind::RateLimited));
+    }
+
+    #[test]
+    fn commands_count_once(){
+        let mut limiter = ChatLimiter::new();
+        // Each sends a message, which is already paid for
+        for i in 0..5 {
+            assert!(limiter.command().is_ok());
+            assert!(limiter.check(Some(&format!("/me waves {i}")), false).is_ok());
+            limiter.command_done();
+        }
+        assert_eq!(limiter.command().map_err(|x| x.0), Err(NoticeKind::RateLimited));
+    }
+
+    #[test]
+    fn commands_that_send_nothing_still_count(){
+        let mut limiter = ChatLimiter::new();
+        for _ in 0..3 {
+            assert!(limiter.command().is_ok());
+            limiter.command_done();
+        }
+        assert!(limiter.check(None, false).is_ok());
+        assert!(limiter.check(None, false).is_ok());
+        assert_eq!(limiter.check(None, false).map_err(|x| x.0), Err(NoticeKind::RateLimited));
+    }
+
+    #[test]
+    fn prepaid_covers_one_message(){
+        let mut limiter = ChatLimiter::new();
+        assert!(limiter.command().is_ok());
+        assert!(limiter.check(Some("first"), false).is_ok());
+        // A second message from the same command costs a token of its own
+        assert!(limiter.check(Some("second"), false).is_ok());
+        limiter.command_done();
+        for i in 0..3 { assert!(limiter.check(Some(&format!("message {i}")), false).is_ok()); }
+        assert!(limiter.check(Some("over"), false).is_err());
+    }
+}
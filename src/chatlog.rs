@@ -0,0 +1,391 @@
+//! Storage for a room's chat log.
+//! Messages are numbered from 0 in the order they were logged. Numbers stay put when old messages are forgotten.
+
+use std::{collections::{HashMap, VecDeque}, fs::{self, File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, mpsc}};
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+use crate::{packets::{ChatMsg, MsgKind}, usersession::SessionId};
+
+/// Somewhere to keep the chat log. Implementations hold a bounded number of the most recent messages.
+pub trait ChatStore: Send + Sync{
+    /// Logs a message, forgetting the oldest one if full
+    fn push(&mut self, entry: ChatMsg);
+    /// Number of the oldest message still held
+    fn first(&self)->usize;
+    /// Number the next message will get
+    fn end(&self)->usize;
+    /// The held messages numbered within start..end
+    fn range(&self, start: usize, end: usize)->Vec<ChatMsg>;
+    /// Forgets every message. Numbering carries on from where it was.
+    fn clear(&mut self);
+    /// Completes once everything logged so far is stored
+    fn flush(&self)->BoxFuture<'static, ()>{
+        Box::pin(async{})
+    }
+}
+
+/// Keeps the most recent messages in a ring buffer. Lost on restart.
+pub struct MemoryStore{
+    entries: VecDeque<ChatMsg>,
+    capacity: usize,
+    // Number of entries[0]
+    first: usize,
+}
+impl MemoryStore{
+    pub fn new(capacity: usize)->Self{
+        Self{ entries: VecDeque::with_capacity(capacity), capacity, first: 0 }
+    }
+}
+impl ChatStore for MemoryStore{
+    fn push(&mut self, entry: ChatMsg){
+        if self.capacity == 0 { self.first += 1; return; }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.first += 1;
+        }
+        self.entries.push_back(entry);
+    }
+    fn first(&self)->usize{ self.first }
+    fn end(&self)->usize{ self.first + self.entries.len() }
+    fn range(&self, start: usize, end: usize)->Vec<ChatMsg>{
+        let start = start.max(self.first) - self.first;
+        let end = end.min(self.end()).saturating_sub(self.first);
+        if start >= end { return vec![]; }
+        return self.entries.range(start..end).cloned().collect();
+    }
+    fn clear(&mut self){
+        self.first = self.end();
+        self.entries.clear();
+    }
+}
+
+lazy_static!{
+    // One thread writes every store's file, so rooms don't cost a thread each
+    static ref WRITER: mpsc::Sender<(u64, Job)> = start_writer();
+}
+// Tells the writer which store a job is for
+static NEXT_STORE: AtomicU64 = AtomicU64::new(0);
+
+/// Appends each message to a JSON-lines file, and reloads the most recent ones from it on startup.
+/// The file is rewritten with only the held messages once it gets to twice the capacity.
+/// Writing happens on a shared thread, so logging a message never waits on the disk. The file is closed once the store is dropped.
+/// A file that doesn't exist yet is only created once there's something to write to it.
+pub struct JsonlStore{
+    mem: MemoryStore,
+    // Lines in the file, once the writer catches up
+    lines: usize,
+    id: u64,
+}
+// What the writer thread does to a store's file, in order
+enum Job{
+    // Starts writing to the file. None until it's created.
+    Open(PathBuf, Option<File>),
+    Append(Vec<u8>),
+    // Replaces the file with just these
+    Rewrite(Vec<ChatMsg>),
+    // Answered once the jobs before it are done
+    Flush(oneshot::Sender<()>),
+    // The store is gone, so the file can be closed
+    Close,
+}
+impl JsonlStore{
+    pub fn open(path: impl Into<PathBuf>, capacity: usize)->std::io::Result<Self>{
+        let path = path.into();
+        let mut mem = MemoryStore::new(capacity);
+        let mut lines = 0;
+        let mut legacy = 0;
+        let mut exists = true;
+        match File::open(&path) {
+            Ok(file) => for line in BufReader::new(file).lines() {
+                let line = line?;
+                if line.trim().is_empty() { continue; }
+                let entry = serde_json::from_str(&line).or_else(|e| {
+                    let old: LegacyEntry = serde_json::from_str(&line).map_err(|_| e)?;
+                    legacy += 1;
+                    Ok::<_, serde_json::Error>(old.upgrade(lines as u64))
+                });
+                match entry {
+                    Ok(entry) => { mem.push(entry); lines += 1; },
+                    Err(e) => warn!("Skipping unreadable chat log line in {} ({e})", path.display()),
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => exists = false,
+            Err(e) => return Err(e),
+        }
+        // Numbering restarts with each run
+        mem.first = 0;
+        info!("Loaded {} chat messages from {}", mem.entries.len(), path.display());
+        if legacy > 0 { info!("Upgraded {} messages in {} from the old log format", legacy, path.display()); }
+
+        let file = if lines > mem.entries.len() || legacy > 0 {
+            lines = mem.entries.len();
+            Some(rewrite(&path, mem.entries.iter())?)
+        } else if exists {
+            Some(OpenOptions::new().append(true).open(&path)?)
+        } else {
+            None
+        };
+        let store = Self{ mem, lines, id: NEXT_STORE.fetch_add(1, Ordering::Relaxed) };
+        store.queue(Job::Open(path, file));
+        return Ok(store);
+    }
+    // Hands a job to the writer
+    fn queue(&self, job: Job){
+        let _ = WRITER.send((self.id, job));
+    }
+}
+impl Drop for JsonlStore{
+    fn drop(&mut self){
+        self.queue(Job::Close);
+    }
+}
+impl ChatStore for JsonlStore{
+    fn push(&mut self, entry: ChatMsg){
+        let mut line = serde_json::to_vec(&entry).unwrap_or_default();
+        line.push(b'\n');
+        self.queue(Job::Append(line));
+        self.lines += 1;
+        self.mem.push(entry);
+        if self.lines >= self.mem.capacity.max(1) * 2 {
+            self.queue(Job::Rewrite(self.mem.entries.iter().cloned().collect()));
+            self.lines = self.mem.entries.len();
+        }
+    }
+    fn first(&self)->usize{ self.mem.first() }
+    fn end(&self)->usize{ self.mem.end() }
+    fn range(&self, start: usize, end: usize)->Vec<ChatMsg>{ self.mem.range(start, end) }
+    fn clear(&mut self){
+        self.mem.clear();
+        self.queue(Job::Rewrite(vec![]));
+        self.lines = 0;
+    }
+    fn flush(&self)->BoxFuture<'static, ()>{
+        let (done, flushed) = oneshot::channel();
+        self.queue(Job::Flush(done));
+        Box::pin(async{ let _ = flushed.await; })
+    }
+}
+
+// A logged message, from before messages had an id, name and kind
+#[derive(Deserialize)]
+struct LegacyEntry{
+    time: u64,
+    // None for the server
+    author: Option<SessionId>,
+    msg: LegacyMsg,
+}
+#[derive(Deserialize)]
+enum LegacyMsg{
+    // "<name>) <text>"
+    User(String),
+    Server(String),
+}
+impl LegacyEntry{
+    // They were numbered by their place in the file, so that's their id
+    fn upgrade(self, id: u64)->ChatMsg{
+        let author = self.author.unwrap_or(SessionId::SERVER);
+        match self.msg {
+            LegacyMsg::User(line) => {
+                let (name, text) = line.split_once(") ").unwrap_or(("", &line));
+                ChatMsg{ id, time: self.time, author, kind: MsgKind::User, name: name.to_owned(), text: text.to_owned() }
+            }
+            LegacyMsg::Server(text) => ChatMsg{ id, time: self.time, author, kind: MsgKind::System, name: String::new(), text },
+        }
+    }
+}
+
+fn start_writer()->mpsc::Sender<(u64, Job)>{
+    let (writer, jobs) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("chatlog".into())
+        .spawn(move || write_jobs(jobs))
+        .expect("Could not start the chat log writer");
+    return writer;
+}
+
+// Runs on the writer thread, holding each open store's file.
+// A failed write only costs us the copy on disk, so chat carries on regardless.
+fn write_jobs(jobs: mpsc::Receiver<(u64, Job)>){
+    let mut files: HashMap<u64, (PathBuf, Option<File>)> = HashMap::new();
+    for (id, job) in jobs {
+        match job {
+            Job::Open(path, file) => { files.insert(id, (path, file)); },
+            Job::Append(line) => if let Some((path, file)) = files.get_mut(&id) {
+                let written = match file {
+                    Some(file) => file.write_all(&line),
+                    None => OpenOptions::new().create(true).append(true).open(&*path)
+                        .and_then(|x| file.insert(x).write_all(&line)),
+                };
+                if let Err(e) = written { warn!("Could not write to the chat log {} ({e})", path.display()); }
+            },
+            Job::Rewrite(entries) => if let Some((path, file)) = files.get_mut(&id) {
+                // Nothing to clear out of a file that was never made
+                if file.is_none() && entries.is_empty() { continue; }
+                match rewrite(path, entries.iter()) {
+                    Ok(x) => *file = Some(x),
+                    Err(e) => warn!("Could not compact the chat log {} ({e})", path.display()),
+                }
+            },
+            Job::Flush(done) => { let _ = done.send(()); },
+            Job::Close => { files.remove(&id); },
+        }
+    }
+}
+
+// Replaces the file with only `entries`, giving it back ready to append to
+fn rewrite<'a>(path: &Path, entries: impl Iterator<Item = &'a ChatMsg>)->std::io::Result<File>{
+    let tmp = path.with_extension("jsonl.tmp");
+    {
+        let mut out = std::io::BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            serde_json::to_writer(&mut out, entry)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+    }
+    fs::rename(&tmp, path)?;
+    return OpenOptions::new().append(true).open(path);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn msg(id: u64)->ChatMsg{
+        ChatMsg{ id, time: 1000 + id, author: SessionId(1), kind: MsgKind::User, name: "Fig001".into(), text: format!("message {id}") }
+    }
+    fn ids(msgs: Vec<ChatMsg>)->Vec<u64>{
+        msgs.into_iter().map(|x| x.id).collect()
+    }
+    // A fresh path in the temp directory, for a log that doesn't exist yet
+    fn temp_log(name: &str)->PathBuf{
+        let path = std::env::temp_dir().join(format!("webrtc-lan-test-{}-{name}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        return path;
+    }
+    fn lines_in(path: &Path)->usize{
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn memory_store_keeps_the_most_recent_in_order(){
+        let mut store = MemoryStore::new(3);
+        assert_eq!((store.first(), store.end()), (0, 0));
+        for id in 0..5 { store.push(msg(id)); }
+        assert_eq!((store.first(), store.end()), (2, 5));
+        assert_eq!(ids(store.range(0, usize::MAX)), [2, 3, 4]);
+    }
+
+    #[test]
+    fn memory_store_ranges(){
+        let mut store = MemoryStore::new(10);
+        for id in 0..15 { store.push(msg(id)); }
+        assert_eq!(ids(store.range(6, 9)), [6, 7, 8]);
+        // Clipped to what's held
+        assert_eq!(ids(store.range(0, 7)), [5, 6]);
+        assert_eq!(ids(store.range(13, 100)), [13, 14]);
+        assert!(store.range(20, 30).is_empty());
+        assert!(store.range(9, 9).is_empty());
+        assert!(store.range(9, 6).is_empty());
+    }
+
+    #[test]
+    fn memory_store_clear_keeps_numbering(){
+        let mut store = MemoryStore::new(4);
+        for id in 0..3 { store.push(msg(id)); }
+        store.clear();
+        assert_eq!((store.first(), store.end()), (3, 3));
+        store.push(msg(3));
+        assert_eq!(ids(store.range(0, 10)), [3]);
+        assert_eq!(store.first(), 3);
+    }
+
+    #[test]
+    fn jsonl_store_reloads_in_order(){
+        let path = temp_log("reload");
+        {
+            let mut store = JsonlStore::open(&path, 5).unwrap();
+            for id in 0..4 { store.push(msg(id)); }
+            futures::executor::block_on(store.flush());
+        }
+        let store = JsonlStore::open(&path, 5).unwrap();
+        assert_eq!((store.first(), store.end()), (0, 4));
+        assert_eq!(ids(store.range(1, 3)), [1, 2]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn jsonl_store_compacts_at_twice_capacity(){
+        let path = temp_log("compact");
+        let mut store = JsonlStore::open(&path, 3).unwrap();
+        for id in 0..5 { store.push(msg(id)); }
+        futures::executor::block_on(store.flush());
+        assert_eq!(lines_in(&path), 5);
+        // The sixth line makes twice the capacity, so only the 3 held are kept
+        store.push(msg(5));
+        futures::executor::block_on(store.flush());
+        assert_eq!(lines_in(&path), 3);
+        store.push(msg(6));
+        futures::executor::block_on(store.flush());
+        assert_eq!(lines_in(&path), 4);
+        assert_eq!(ids(store.range(0, usize::MAX)), [4, 5, 6]);
+        drop(store);
+
+        // Reloading numbers the held messages from 0
+        let store = JsonlStore::open(&path, 3).unwrap();
+        assert_eq!((store.first(), store.end()), (0, 3));
+        assert_eq!(ids(store.range(0, usize::MAX)), [4, 5, 6]);
+        assert_eq!(lines_in(&path), 3);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn jsonl_store_upgrades_old_lines(){
+        let path = temp_log("legacy");
+        fs::write(&path, concat!(
+            r#"{"time":5,"author":null,"msg":{"Server":"Fig001 has joined"}}"#, "\n",
+            r#"{"time":6,"author":42,"msg":{"User":"Fig001) hi :) there"}}"#, "\n",
+        )).unwrap();
+        let store = JsonlStore::open(&path, 5).unwrap();
+        let msgs = store.range(0, usize::MAX);
+        assert_eq!(ids(msgs.clone()), [0, 1]);
+        assert_eq!((msgs[0].kind, msgs[0].author, msgs[0].text.as_str()), (MsgKind::System, SessionId::SERVER, "Fig001 has joined"));
+        assert_eq!((msgs[1].kind, msgs[1].author, msgs[1].time), (MsgKind::User, SessionId(42), 6));
+        assert_eq!((msgs[1].name.as_str(), msgs[1].text.as_str()), ("Fig001", "hi :) there"));
+        drop(store);
+        // Rewritten in the current format
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.lines().all(|x| serde_json::from_str::<ChatMsg>(x).is_ok()));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn jsonl_store_clear_empties_the_file(){
+        let path = temp_log("clear");
+        let mut store = JsonlStore::open(&path, 3).unwrap();
+        for id in 0..3 { store.push(msg(id)); }
+        store.clear();
+        futures::executor::block_on(store.flush());
+        assert_eq!(lines_in(&path), 0);
+        assert!(store.range(0, usize::MAX).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn jsonl_store_creates_the_file_on_first_message(){
+        let path = temp_log("lazy");
+        let mut store = JsonlStore::open(&path, 3).unwrap();
+        store.clear();
+        futures::executor::block_on(store.flush());
+        assert!(!path.exists());
+        store.push(msg(0));
+        futures::executor::block_on(store.flush());
+        assert_eq!(lines_in(&path), 1);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,136 +1,407 @@
-use std::{collections::HashMap, future::Future, sync::Arc};
+use std::{collections::HashMap, future::Future, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use lazy_static::lazy_static;
 use log::{info, warn};
 use tokio::sync::{broadcast, mpsc, RwLock, RwLockWriteGuard};
 
-use crate::{fi, packets::{Encode, PktS2C_LobbyInfo}, usersession::{ActiveSession, SessionId, UserSession}};
+use crate::{chatlog::{ChatStore, JsonlStore, MemoryStore}, config, fi, packets::{Capabilities, ChatMsg, Encode, Link, MsgKind, Participant, PktS2C_ChatHistory, PktS2C_LobbyInfo, PktS2C_ParticipantJoined, PktS2C_ParticipantLeft, PktS2C_ParticipantUpdated, PktS2C_RoomJoined, NameResult, PktS2C_SetNameReply, Role}, usersession::{self, ActiveSession, SessionId, SharedUser}};
 
-#[derive(Clone)]
-pub enum ChatMsg{
-    User(String),
-    Server(String),
+/// The most messages a client can fetch at once
+pub const HISTORY_PAGE_MAX: usize = 100;
+/// The room everyone starts in
+pub const DEFAULT_ROOM: &str = "lobby";
+/// Longest allowed room name
+const ROOM_NAME_MAX: usize = 32;
+
+// Carries on from the highest id in the chat logs, so ids stay unique across restarts
+static NEXT_MSG_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A new message, stamped with the next id and the current time
+pub fn chat_msg(kind: MsgKind, author: SessionId, name: String, text: String)->ChatMsg{
+    let id = NEXT_MSG_ID.fetch_add(1, Ordering::Relaxed);
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0);
+    return ChatMsg{ id, time, author, kind, name, text };
+}
+/// A new message from the server
+pub fn system_msg(text: String)->ChatMsg{
+    return chat_msg(MsgKind::System, SessionId::SERVER, String::new(), text);
 }
+
 #[derive(Clone)]
 pub enum ParticipantMsg{
     Message(ChatMsg),
-    RawPacket(Vec<u8>)
+    RawPacket(Vec<u8>),
+    /// Ends the session, telling the client why
+    Disconnect(String),
 }
 
-// Global lobby
+// Global room registry
 lazy_static!{
-    pub static ref LOBBY: Lobby = Lobby::new();
+    pub static ref ROOMS: RoomRegistry = RoomRegistry::load();
+}
+
+/// All the chat rooms. Rooms come back on startup if they have a chat log.
+/// Rooms other than the default one are closed once nobody is using them, if nothing was ever said in them.
+pub struct RoomRegistry{
+    rooms: RwLock<HashMap<String, Arc<Lobby>>>,
+    default: Arc<Lobby>,
+}
+impl RoomRegistry{
+    // Creates the default room, and reopens every room that left a chat log behind
+    fn load()->Self{
+        let dir = &config::get().chatlog_dir;
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            warn!("Could not create the chat log directory {} ({e})", dir.display());
+        }
+        let mut rooms = HashMap::new();
+        let logs = std::fs::read_dir(dir).into_iter().flatten().flatten().map(|x| x.path());
+        for path in logs.filter(|x| x.extension().is_some_and(|x| x == "jsonl")) {
+            let Some(name) = path.file_stem().and_then(|x| x.to_str()).and_then(room_name) else { continue; };
+            rooms.insert(name.clone(), Arc::new(Lobby::open(name)));
+        }
+        let default = rooms.entry(DEFAULT_ROOM.to_owned())
+            .or_insert_with(|| Arc::new(Lobby::open(DEFAULT_ROOM.to_owned())))
+            .clone();
+        info!("Rooms: {:?}", rooms.keys().collect::<Vec<_>>());
+        return Self{ rooms: RwLock::new(rooms), default };
+    }
+    pub fn default_room(&self)->Arc<Lobby>{
+        self.default.clone()
+    }
+    pub async fn get(&self, name: &str)->Option<Arc<Lobby>>{
+        self.rooms.read().await.get(name).cloned()
+    }
+    /// Gets the room, creating it if it doesn't exist yet. `name` must have come from `room_name`.
+    /// None if it would have to be created, but there are already `max_rooms`.
+    pub async fn get_or_create(&self, name: String)->Option<Arc<Lobby>>{
+        let mut rooms = self.rooms.write().await;
+        if let Some(room) = rooms.get(&name) { return Some(room.clone()); }
+        close_unused(&mut rooms).await;
+        if rooms.len() >= config::get().max_rooms { return None; }
+        info!("Created room {}", name);
+        let room = Arc::new(Lobby::open(name.clone()));
+        rooms.insert(name, room.clone());
+        return Some(room);
+    }
+    /// Every room, sorted by name
+    pub async fn all(&self)->Vec<Arc<Lobby>>{
+        let mut rooms: Vec<Arc<Lobby>> = self.rooms.read().await.values().cloned().collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        return rooms;
+    }
+    /// Completes once every room's chat log is stored
+    pub async fn flush_logs(&self){
+        for room in self.all().await {
+            let flushed = room.sync.read().await.log.flush();
+            flushed.await;
+        }
+    }
+    /// Names of all rooms, sorted
+    pub async fn list(&self)->Vec<String>{
+        let mut rooms = self.rooms.write().await;
+        close_unused(&mut rooms).await;
+        let mut names: Vec<String> = rooms.keys().cloned().collect();
+        names.sort();
+        return names;
+    }
+}
+
+// Drops the rooms nobody has a hold of (no members, sessions waiting to resume or anyone joining) that nothing was said in.
+// Only the registry hands out rooms, so one it holds the only reference to can't be joined while it's locked.
+async fn close_unused(rooms: &mut HashMap<String, Arc<Lobby>>){
+    let mut unused = vec![];
+    for (name, room) in rooms.iter() {
+        if name == DEFAULT_ROOM || Arc::strong_count(room) > 1 { continue; }
+        if room.sync.read().await.log.end() == 0 { unused.push(name.clone()); }
+    }
+    for name in unused {
+        info!("Closed unused room {}", name);
+        rooms.remove(&name);
+    }
 }
 
-/// Represents the chat lobby
+/// Normalises a requested room name, or None if it isn't allowed.
+/// Names double as file names, so they are kept to lowercase letters, digits, '-' and '_'.
+pub fn room_name(name: &str)->Option<String>{
+    let name = name.trim().to_lowercase();
+    let valid = !name.is_empty() && name.len() <= ROOM_NAME_MAX
+        && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_');
+    return fi!(valid, Some(name), None);
+}
+
+/// Represents a chat room
 pub struct Lobby{
+    pub name: String,
     sync: RwLock<LobbySync>,
     pub broadcast_tx: broadcast::Sender<ParticipantMsg>,
+    // Number of recent messages sent to new participants
+    history_replay: usize,
 }
 unsafe impl Sync for Lobby{}
 /// Contains the parts of the chat that must be synchronised in their modification
 pub struct LobbySync{
-    log: Vec<ChatMsg>,
+    log: Box<dyn ChatStore>,
     // Maps client session ids with their individual send channel - used so the lobby can send directly to a single person (e.g.: Name changes)
     members: HashMap<SessionId, LobbyMember>,
 }
 
 pub struct LobbyMember{
     send: mpsc::Sender<ParticipantMsg>,
-    view: Arc<RwLock<UserSession>>, // NOTE: We never have dead members because LobbyHandle removes from the view
+    view: SharedUser, // NOTE: We never have dead members because LobbyHandle removes from the view
+    // Lost while the session waits to be resumed. The member stays in the lobby meanwhile.
+    link: Link,
 }
 
 /// A client's handle to the lobby.
-/// Destroying this object exits the session from the lobby.
+/// Destroying this object exits the session from the lobby, unless it was detached.
 pub struct LobbyHandle{
     pub broadcast_rx: broadcast::Receiver<ParticipantMsg>,
     pub individual_rx: mpsc::Receiver<ParticipantMsg>,
+    room: Arc<Lobby>,
     // The session associated with this handle.
-    sessionid: SessionId
+    sessionid: SessionId,
+    // Leave the lobby on drop
+    active: bool,
 }
 
 //
 impl Lobby{
-    pub fn new()->Self{
-        let (broadcast_tx, _) = broadcast::channel(64);
+    pub fn new(name: String, history_replay: usize, log: Box<dyn ChatStore>)->Self{
+        let (broadcast_tx, _) = broadcast::channel(config::get().channel_capacity);
         Self {
+            name,
             sync: RwLock::new(LobbySync {
-                log: vec![],
+                log,
                 members: HashMap::new()
             }),
-            broadcast_tx
+            broadcast_tx,
+            history_replay,
+        }
+    }
+    // A room with its log kept in the chat log directory.
+    // Falls back to keeping the log in memory if the file can't be used.
+    fn open(name: String)->Self{
+        let config = config::get();
+        let path = config.chatlog_dir.join(format!("{name}.jsonl"));
+        let log: Box<dyn ChatStore> = match JsonlStore::open(&path, config.chatlog_capacity) {
+            Ok(store) => Box::new(store),
+            Err(e) => {
+                warn!("Could not open the chat log {} ({e}). Chat history will not survive a restart.", path.display());
+                Box::new(MemoryStore::new(config.chatlog_capacity))
+            }
+        };
+        if let Some(last) = log.range(log.end().saturating_sub(1), log.end()).pop() {
+            NEXT_MSG_ID.fetch_max(last.id + 1, Ordering::Relaxed);
         }
+        return Self::new(name, config.history_replay, log);
     }
     // Joins the lobby.
     // Registers the sessionid in the lobby struct, and returns a handle that receives both broadcast and individual messages.
-    // For a lobby participant to send to the lobby, access through the global LOBBY
-    pub async fn join(&self, session: &ActiveSession)->LobbyHandle{
-        // Send a join message to all other participants
-        let announcement = format!(">>> {} has joined", session.user().await.username);
-        let _ = self.broadcast_tx.send(ParticipantMsg::Message(ChatMsg::Server(announcement)));
+    // A resumed session that is still a (detached) member quietly takes its place back.
+    // For a lobby participant to send to the lobby, access through LobbyHandle::room
+    pub async fn join(self: &Arc<Self>, session: &ActiveSession)->LobbyHandle{
+        let (sid, username) = { let u = session.user().await; (u.id, u.username.clone()) };
+        let rejoining = self.sync.read().await.members.contains_key(&sid);
+        if !rejoining {
+            // Send a join message to all other participants
+            let announcement = format!("{} has joined", username);
+            let _ = self.broadcast_tx.send(ParticipantMsg::Message(system_msg(announcement)));
+        }
 
         // Create the lobby handle
-        let broadcast_rx = self.broadcast_tx.subscribe();
-        let (individual_tx, individual_rx) = mpsc::channel(64);
-                // Send welcome
-                let welcome = format!(">>> Welcome, {}.", session.user().await.username);
-                let _ = individual_tx.send(ParticipantMsg::Message(ChatMsg::Server(welcome))).await;
+        // Subscribing under the lock means the history lines up exactly with the first broadcast message.
+        let (broadcast_rx, history) = {
+            let sync = self.sync.read().await;
+            let replay = !rejoining && self.history_replay > 0 && session.caps().contains(Capabilities::HISTORY);
+            (self.broadcast_tx.subscribe(), fi!(replay, Some(sync.history_page(usize::MAX, self.history_replay)), None))
+        };
+        let (individual_tx, individual_rx) = mpsc::channel(config::get().channel_capacity);
         let member = LobbyMember{
-            send: individual_tx,
+            send: individual_tx.clone(),
             view: session.user.clone(),
+            link: Link::Good,
         };
-        self.write_sync().await.members.insert(session.user().await.id, member);
-        let handle = LobbyHandle{ broadcast_rx, individual_rx, sessionid: session.user().await.id };
+        // Catch them up on the conversation
+        if let Some(history) = history.filter(|x| !x.msgs.is_empty()) {
+            let _ = member.send.send(ParticipantMsg::RawPacket(history.encode())).await;
+        }
+        // Send welcome
+        let welcome = fi!(rejoining, format!("Welcome back, {}.", username), format!("Welcome, {}.", username));
+        let _ = member.send.send(ParticipantMsg::Message(system_msg(welcome))).await;
+        let joined = Self::participant(sid, &member).await;
+        self.write_sync().await.members.insert(sid, member);
+        let handle = LobbyHandle{ broadcast_rx, individual_rx, room: self.clone(), sessionid: sid, active: true };
 
-        // update_participants
-        self.update_lobby_participants().await;
+        // Tell everyone else, then give the newcomer the whole list
+        let delta = fi!(rejoining, PktS2C_ParticipantUpdated::new(joined).encode(), PktS2C_ParticipantJoined::new(joined).encode());
+        let _ = self.broadcast_tx.send(ParticipantMsg::RawPacket(delta));
+        let list = PktS2C_LobbyInfo::new(self.participants().await).encode();
+        let _ = individual_tx.send(ParticipantMsg::RawPacket(list)).await;
         return handle;
     }
     // Removes a member & broadcasts the new lobby participant table
     pub async fn remove(&self, sessionid: SessionId){
         let Some(session) = self.write_sync().await.members.remove(&sessionid) else {
-            warn!("Attempt to remove non-existent session {} from the lobby", sessionid);
+            warn!("Attempt to remove non-existent session {} from room {}", sessionid, self.name);
             return;
         };
 
         let username = session.view.read().await.username.clone();
-        let announcement = format!(">>> {} has left.", username);
-        let _ = self.broadcast_tx.send(ParticipantMsg::Message(ChatMsg::Server(announcement)));
-
-        info!("Removed session {}", sessionid);
-        self.update_lobby_participants().await;
-    }
-    // Broadcasts a list of lobby participants to all clients
-    pub async fn update_lobby_participants(&self){
-        // Network programming is so different to your run-of-the-mill sequence of operations.
-        // This is not technically optimal because I should run futures for each of these reads.
-        // ARRGH.
-        let mut list = vec![];
-        for x in self.sync.read().await.members.iter() {
-            let (name, hand) = {
-                let x = x.1.view.read().await;
-                (x.username.clone(), x.raised_hand)
-            };
-            list.push(format!("{}{}", fi!(hand, "👋", ""), name));
-        }
-        let packet = PktS2C_LobbyInfo::new(list).encode();
+        let announcement = format!("{} has left.", username);
+        let _ = self.broadcast_tx.send(ParticipantMsg::Message(system_msg(announcement)));
+
+        info!("Removed session {} from room {}", sessionid, self.name);
+        let _ = self.broadcast_tx.send(ParticipantMsg::RawPacket(PktS2C_ParticipantLeft::new(sessionid).encode()));
+    }
+    // Marks a member as having lost its connection. It stays in the lobby until resumed or removed.
+    pub async fn detach(&self, sessionid: SessionId){
+        self.set_link(sessionid, Link::Lost).await;
+    }
+    /// Updates how well the member is connected, telling everyone if it changed
+    pub async fn set_link(&self, sessionid: SessionId, link: Link){
+        let mut sync = self.write_sync().await;
+        let Some(member) = sync.members.get_mut(&sessionid) else { return; };
+        if member.link == link { return; }
+        member.link = link;
+        let packet = PktS2C_ParticipantUpdated::new(Self::participant(sessionid, member).await).encode();
         let _ = self.broadcast_tx.send(ParticipantMsg::RawPacket(packet));
     }
+    /// Tells everyone about a change to the member's name, hand or role
+    pub async fn update_participant(&self, sessionid: SessionId){
+        let sync = self.sync.read().await;
+        let Some(member) = sync.members.get(&sessionid) else { return; };
+        let packet = PktS2C_ParticipantUpdated::new(Self::participant(sessionid, member).await).encode();
+        let _ = self.broadcast_tx.send(ParticipantMsg::RawPacket(packet));
+    }
+    /// Renames a member, telling them and everyone else. The name should have been through `names::check`.
+    pub async fn rename(&self, sessionid: SessionId, name: String){
+        let Some((user, send)) = self.sync.read().await.members.get(&sessionid).map(|x| (x.view.clone(), x.send.clone())) else { return; };
+        let old = std::mem::replace(&mut user.write().await.username, name.clone());
+        let _ = send.send(ParticipantMsg::RawPacket(PktS2C_SetNameReply::new(NameResult::Accepted, name.clone()).encode())).await;
+        if old != name {
+            let _ = self.broadcast_tx.send(ParticipantMsg::Message(system_msg(format!("{} is now {}", old, name))));
+            self.update_participant(sessionid).await;
+        }
+    }
+    /// Sends a direct message packet to one member, through their own channel. It isn't logged.
+    /// False if they aren't in the room or aren't connected.
+    pub async fn whisper(&self, to: SessionId, packet: Vec<u8>)->bool{
+        let Some(send) = self.sync.read().await.members.get(&to).map(|x| x.send.clone()) else { return false; };
+        return send.send(ParticipantMsg::RawPacket(packet)).await.is_ok();
+    }
+    /// Ends a member's session and removes them from the room
+    pub async fn kick(&self, sessionid: SessionId, reason: String){
+        let Some((user, send)) = self.sync.read().await.members.get(&sessionid).map(|x| (x.view.clone(), x.send.clone())) else { return; };
+        let announcement = format!("{} was kicked ({})", user.read().await.username, reason);
+        let _ = self.broadcast_tx.send(ParticipantMsg::Message(system_msg(announcement)));
+        if send.send(ParticipantMsg::Disconnect(reason)).await.is_err() {
+            // Nobody is listening: the session is waiting to be resumed
+            usersession::forget_session(sessionid);
+            self.remove(sessionid).await;
+        }
+    }
+    /// Stops the member chatting or changing their name for a while. None lets them again.
+    pub async fn set_muted(&self, sessionid: SessionId, duration: Option<Duration>){
+        let Some((user, send)) = self.sync.read().await.members.get(&sessionid).map(|x| (x.view.clone(), x.send.clone())) else { return; };
+        let was_muted = {
+            let mut user = user.write().await;
+            let was_muted = user.muted();
+            user.muted_until = duration.map(|x| Instant::now() + x);
+            was_muted
+        };
+        let notice = match duration{
+            Some(x) => format!("You have been muted by the host for {} minutes.", x.as_secs().div_ceil(60)),
+            None if was_muted => "You can chat again.".to_owned(),
+            None => return,
+        };
+        let _ = send.send(ParticipantMsg::Message(system_msg(notice))).await;
+    }
+    /// Gives the member a new role, telling them and everyone else
+    pub async fn set_role(&self, sessionid: SessionId, role: Role){
+        let Some((user, send)) = self.sync.read().await.members.get(&sessionid).map(|x| (x.view.clone(), x.send.clone())) else { return; };
+        if std::mem::replace(&mut user.write().await.role, role) == role { return; }
+        let notice = format!("You are now a {}.", format!("{:?}", role).to_lowercase());
+        let _ = send.send(ParticipantMsg::Message(system_msg(notice))).await;
+        self.update_participant(sessionid).await;
+    }
+    /// Forgets the room's chat log
+    pub async fn clear_log(&self){
+        self.write_sync().await.log.clear();
+        let _ = self.broadcast_tx.send(ParticipantMsg::Message(system_msg("The chat log was cleared.".into())));
+    }
+    /// Everyone in the room, with their sessions
+    pub async fn members(&self)->Vec<(Participant, SharedUser)>{
+        let sync = self.sync.read().await;
+        let mut list = Vec::with_capacity(sync.members.len());
+        for (sid, member) in sync.members.iter() {
+            list.push((Self::participant(*sid, member).await, member.view.clone()));
+        }
+        return list;
+    }
+    /// Everyone in the room
+    pub async fn participants(&self)->Vec<Participant>{
+        let sync = self.sync.read().await;
+        let mut list = Vec::with_capacity(sync.members.len());
+        for (sid, member) in sync.members.iter() {
+            list.push(Self::participant(*sid, member).await);
+        }
+        return list;
+    }
+    async fn participant(sid: SessionId, member: &LobbyMember)->Participant{
+        let user = member.view.read().await;
+        return Participant::new(sid, user.username.clone(), user.raised_hand, member.link, user.role);
+    }
 
+    /// Sends a message to everyone and logs it
     pub async fn send_message(&self, msg: ChatMsg){
+        // Broadcast under the lock so that joining participants see each message exactly once
+        let mut sync = self.write_sync().await;
         let _ = self.broadcast_tx.send(ParticipantMsg::Message(msg.clone()));
-        self.write_sync().await.log.push(msg);
+        sync.log.push(msg);
+    }
+
+    /// Up to `count` logged messages preceding message number `before`.
+    pub async fn history_page(&self, before: usize, count: usize)->PktS2C_ChatHistory{
+        self.sync.read().await.history_page(before, count)
     }
 
     fn write_sync(&self)->impl Future<Output = RwLockWriteGuard<'_, LobbySync>>{
         self.sync.write()
     }
 }
+impl LobbySync{
+    fn history_page(&self, before: usize, count: usize)->PktS2C_ChatHistory{
+        let end = before.min(self.log.end());
+        let start = end.saturating_sub(count).max(self.log.first());
+        PktS2C_ChatHistory::new(start as u64, start > self.log.first(), self.log.range(start, end))
+    }
+}
+impl LobbyHandle{
+    pub fn room(&self)->&Arc<Lobby>{
+        &self.room
+    }
+    /// Gives up the handle without leaving the lobby, so the session can be resumed later.
+    pub async fn detach(mut self){
+        self.active = false;
+        self.room.detach(self.sessionid).await;
+    }
+    /// Leaves the lobby now, rather than in the background like dropping the handle does.
+    pub async fn leave(mut self){
+        self.active = false;
+        self.room.remove(self.sessionid).await;
+    }
+    /// Tells the client which room it's in
+    pub fn joined_packet(&self)->Vec<u8>{
+        PktS2C_RoomJoined::new(self.room.name.clone()).encode()
+    }
+}
 impl std::ops::Drop for LobbyHandle{
     // Leaves the lobby
     fn drop(&mut self) {
+        if !self.active { return; }
         let sid = self.sessionid;
+        let room = self.room.clone();
         tokio::spawn(async move{
-            LOBBY.remove(sid).await;
+            room.remove(sid).await;
         });
     }
 }
\ No newline at end of file
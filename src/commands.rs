@@ -0,0 +1,210 @@
+//! Chat commands: messages starting with '/' are looked up here rather than sent to the room.
+//! The built-ins are below. Other modules can add their own with `register`.
+
+use std::sync::{Arc, RwLock};
+
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
+use log::info;
+use rand::Rng;
+
+use crate::{chatroom::{chat_msg, Lobby}, packets::{MsgKind, NoticeKind, Role}, usersession::{ActiveSession, Permission}};
+
+/// Most dice /roll can throw at once
+const MAX_DICE: u32 = 20;
+/// Most sides a die can have
+const MAX_SIDES: u32 = 1000;
+
+lazy_static!{
+    static ref COMMANDS: RwLock<Vec<Command>> = RwLock::new(builtins());
+}
+
+/// Splits a command's arguments up, or None if they don't fit
+pub type Parser = fn(&str)->Option<Vec<String>>;
+/// Carries out a command. What it returns is told to the client.
+pub type Handler = for<'a> fn(Invocation<'a>)->BoxFuture<'a, Option<String>>;
+
+/// Everything a command gets to work with
+pub struct Invocation<'a>{
+    pub session: &'a ActiveSession,
+    pub room: &'a Arc<Lobby>,
+    /// As split up by the command's parser
+    pub args: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct Command{
+    pub name: &'static str,
+    /// Other names it answers to
+    pub aliases: &'static [&'static str],
+    /// What goes after the name, for /help. e.g.: "<name> <message>"
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// Needed to use it, if anything
+    pub permission: Option<Permission>,
+    pub parse: Parser,
+    pub run: Handler,
+}
+
+/// Adds a command, replacing any with the same name
+pub fn register(command: Command){
+    let mut commands = COMMANDS.write().unwrap();
+    commands.retain(|x| x.name != command.name);
+    commands.push(command);
+}
+
+fn find(name: &str)->Option<Command>{
+    let name = name.to_lowercase();
+    return COMMANDS.read().unwrap().iter().find(|x| x.name == name || x.aliases.contains(&name.as_str())).cloned();
+}
+
+/// Runs a command line (without its '/') for the session, telling the client what came of it.
+/// Every command counts as a message against the rate limit, whatever comes of it. One it then sends doesn't count twice.
+pub async fn run(session: &ActiveSession, room: &Arc<Lobby>, line: &str){
+    let charged = session.user.write().await.chat.command();
+    if let Err((kind, text)) = charged {
+        info!("Refused a command from {} ({:?})", session.user().await.username, kind);
+        return session.notice(kind, text).await;
+    }
+    dispatch(session, room, line).await;
+    session.user.write().await.chat.command_done();
+}
+
+async fn dispatch(session: &ActiveSession, room: &Arc<Lobby>, line: &str){
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let Some(command) = find(name) else {
+        return session.notice(NoticeKind::Command, format!("There's no /{} command. /help lists them.", name)).await;
+    };
+    if let Some(permission) = command.permission {
+        if !session.user().await.can(permission) {
+            return session.notice(NoticeKind::Command, format!("You need to be a {} to use /{}.", format!("{:?}", permission.min_role()).to_lowercase(), command.name)).await;
+        }
+    }
+    let Some(args) = (command.parse)(args.trim()) else {
+        return session.notice(NoticeKind::Command, format!("Usage: /{} {}", command.name, command.usage)).await;
+    };
+    if let Some(output) = (command.run)(Invocation{ session, room, args }).await {
+        session.notice(NoticeKind::Command, output).await;
+    }
+}
+
+/// Takes no arguments
+pub fn no_args(args: &str)->Option<Vec<String>>{
+    return args.is_empty().then(Vec::new);
+}
+/// Takes the rest of the line as one argument, which can't be empty
+pub fn text(args: &str)->Option<Vec<String>>{
+    return (!args.is_empty()).then(|| vec![args.to_owned()]);
+}
+
+fn builtins()->Vec<Command>{
+    return vec![
+        Command{ name: "help", aliases: &[], usage: "", help: "Lists the commands you can use", permission: None, parse: no_args, run: help },
+        Command{ name: "me", aliases: &[], usage: "<action>", help: "Describes what you're doing", permission: Some(Permission::Chat), parse: text, run: me },
+        Command{ name: "nick", aliases: &["name"], usage: "<name>", help: "Changes your name", permission: Some(Permission::Rename), parse: text, run: nick },
+        Command{ name: "who", aliases: &[], usage: "", help: "Lists everyone in the room", permission: None, parse: no_args, run: who },
+        Command{ name: "roll", aliases: &[], usage: "[NdM]", help: "Rolls N dice with M sides, one 6 sided die by default", permission: Some(Permission::Chat), parse: dice, run: roll },
+        Command{ name: "whisper", aliases: &["w", "msg"], usage: "<name> <message>", help: "Sends a message only they can see", permission: Some(Permission::Chat), parse: text, run: whisper },
+    ];
+}
+
+fn help(cx: Invocation<'_>)->BoxFuture<'_, Option<String>>{ Box::pin(async move{
+    let role = cx.session.user().await.role;
+    let commands = COMMANDS.read().unwrap().clone();
+    let lines: Vec<String> = commands.iter()
+        .filter(|x| x.permission.is_none_or(|p| role >= p.min_role()))
+        .map(|x| format!("{} - {}", format!("/{} {}", x.name, x.usage).trim_end(), x.help))
+        .collect();
+    return Some(format!("Commands:\n{}\nStart a message with // to send it with one slash.", lines.join("\n")));
+})}
+
+fn me(cx: Invocation<'_>)->BoxFuture<'_, Option<String>>{ Box::pin(async move{
+    cx.session.say(cx.room, MsgKind::Action, &cx.args[0]).await;
+    return None;
+})}
+
+fn nick(cx: Invocation<'_>)->BoxFuture<'_, Option<String>>{ Box::pin(async move{
+    cx.session.set_name(cx.room, &cx.args[0]).await;
+    return None;
+})}
+
+fn who(cx: Invocation<'_>)->BoxFuture<'_, Option<String>>{ Box::pin(async move{
+    let mut participants = cx.room.participants().await;
+    participants.sort_by(|a, b| b.role.cmp(&a.role).then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase())));
+    let names: Vec<String> = participants.iter()
+        .map(|p| match p.role{
+            Role::Member => p.name.clone(),
+            role => format!("{} ({})", p.name, format!("{:?}", role).to_lowercase()),
+        })
+        .collect();
+    return Some(format!("In {} ({}): {}", cx.room.name, names.len(), names.join(", ")));
+})}
+
+// NdM, dM or M. Gives the number of dice, then sides.
+fn dice(args: &str)->Option<Vec<String>>{
+    if args.is_empty() { return Some(vec!["1".into(), "6".into()]); }
+    let (count, sides) = args.to_lowercase().split_once('d').map(|(n, m)| (n.to_owned(), m.to_owned())).unwrap_or(("1".into(), args.to_owned()));
+    let count = if count.is_empty() { 1 } else { count.parse::<u32>().ok()? };
+    let sides = sides.parse::<u32>().ok()?;
+    if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) { return None; }
+    return Some(vec![count.to_string(), sides.to_string()]);
+}
+
+fn roll(cx: Invocation<'_>)->BoxFuture<'_, Option<String>>{ Box::pin(async move{
+    let (count, sides): (u32, u32) = (cx.args[0].parse().unwrap_or(1), cx.args[1].parse().unwrap_or(6));
+    // Rolled before anything is awaited, as the generator can't be held across it
+    let rolls: Vec<u32> = { let mut rng = rand::thread_rng(); (0..count).map(|_| rng.gen_range(1..=sides)).collect() };
+    // Counts as a message, but the result isn't checked for repeats. Refusals are told to the client by may_chat.
+    if let Some((sid, name)) = cx.session.may_chat(None).await {
+        let total: u32 = rolls.iter().sum();
+        let text = match rolls.len(){
+            1 => format!("rolls a d{}: {}", sides, total),
+            _ => format!("rolls {}d{}: {} = {}", count, sides, rolls.iter().map(u32::to_string).collect::<Vec<_>>().join(" + "), total),
+        };
+        cx.room.send_message(chat_msg(MsgKind::Action, sid, name, text)).await;
+    }
+    return None;
+})}
+
+// The longest name in the room that the text starts with is who it's for, as names can have spaces
+fn whisper(cx: Invocation<'_>)->BoxFuture<'_, Option<String>>{ Box::pin(async move{
+    let text = &cx.args[0];
+    let target = cx.room.participants().await.into_iter()
+        .filter_map(|p| after_name(text, &p.name).map(|msg| (p, msg)))
+        .max_by_key(|(p, _)| p.name.chars().count());
+    let Some((target, msg)) = target else { return Some("No one here goes by that name. Usage: /whisper <name> <message>".into()); };
+    cx.session.whisper(cx.room, target.sid, msg).await;
+    return None;
+})}
+
+// What follows `name` and a space at the start of `text`, ignoring case.
+// Compared a character at a time, as changing case can change how many bytes a character takes.
+fn after_name<'a>(text: &'a str, name: &str)->Option<&'a str>{
+    let mut rest = text.chars();
+    for x in name.chars() {
+        let y = rest.next()?;
+        if !x.to_lowercase().eq(y.to_lowercase()) { return None; }
+    }
+    return rest.as_str().strip_prefix(' ');
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn whisper_names_ignore_case(){
+        assert_eq!(after_name("fig001 hi there", "Fig001"), Some("hi there"));
+        assert_eq!(after_name("Big Fig hi", "big fig"), Some("hi"));
+        assert_eq!(after_name("Fig0012 hi", "Fig001"), None);
+        assert_eq!(after_name("Fig001", "Fig001"), None);
+    }
+
+    #[test]
+    fn whisper_names_that_change_length_with_case(){
+        // The Kelvin sign lowercases to a one byte k, and İ to two characters
+        assert_eq!(after_name("\u{212A}im hello", "kim"), Some("hello"));
+        assert_eq!(after_name("kim hello", "\u{212A}im"), Some("hello"));
+        assert_eq!(after_name("İPEK selam", "İpek"), Some("selam"));
+    }
+}
@@ -0,0 +1,331 @@
+//! Runtime configuration. Read from a TOML file, then overridden by environment variables, then by command-line flags.
+//! `--help` lists the flags and the environment variable behind each.
+
+use std::{net::{IpAddr, Ipv4Addr}, path::PathBuf, sync::OnceLock, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use log::{warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use webrtc::ice::url::{SchemeType, Url};
+
+/// Read when no other config file is given, if it exists
+const DEFAULT_PATH: &str = "webrtc-lan.toml";
+/// What WEBRTC_LAN_HOST_PASSPHRASE used to be called. Still read, with a warning.
+const OLD_PASSPHRASE_VAR: &str = "HOST_PASSPHRASE";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config{
+    /// Address the webserver listens on
+    pub host: IpAddr,
+    pub port: u16,
+    /// Seconds a client gets to connect over WebRTC after its offer is answered
+    pub connect_timeout: u64,
+    /// Messages each room and each session can have waiting to be sent
+    pub channel_capacity: usize,
+    pub log_level: LevelFilter,
+    /// Log level of the ICE agent, which is chatty
+    pub ice_log_level: LevelFilter,
+    /// Longest chat message allowed, in bytes
+    pub max_message_bytes: usize,
+    /// Messages a session can send in quick succession
+    pub message_burst: u32,
+    /// Messages per second a session can keep up
+    pub message_rate: f64,
+    /// Seconds each session has to wait between messages. 0 is off. Moderators and hosts are exempt.
+    pub slow_mode: u64,
+    /// Seconds a session can't repeat one of its messages for. 0 is off.
+    pub repeat_window: u64,
+    /// Most recent messages shown to someone joining a room. 0 is off.
+    pub history_replay: usize,
+    /// Messages each room's chat log holds on to
+    pub chatlog_capacity: usize,
+    /// Most rooms there can be, counting the default one
+    pub max_rooms: usize,
+    /// Directory each room's chat log is kept in, as `<room>.jsonl`
+    pub chatlog_dir: PathBuf,
+    /// File the bans are kept in
+    pub bans_path: PathBuf,
+    /// Given in ClaimHost to become host. Claiming is off when unset.
+    pub host_passphrase: Option<String>,
+    /// New sessions are named one of these followed by 3 digits
+    pub usernames: Vec<String>,
+    /// STUN and TURN servers the server gathers candidates from. None are needed on a LAN.
+    pub ice_servers: Vec<IceServer>,
+    /// Which kinds of candidate the server offers clients
+    pub ice_candidates: IceCandidates,
+    /// Network interfaces candidates are gathered on, by name. Empty for all of them.
+    pub ice_interfaces: Vec<String>,
+    /// Network interfaces never gathered on, by name
+    pub ice_interfaces_deny: Vec<String>,
+    /// Every peer shares this one UDP port. Otherwise each gets its own.
+    pub ice_udp_port: Option<u16>,
+    /// Lowest and highest UDP port peers can be given, when not sharing one
+    pub ice_port_min: Option<u16>,
+    pub ice_port_max: Option<u16>,
+    /// Advertised in place of the server's own address, for when it's behind a 1:1 NAT (e.g.: in a Docker container)
+    pub public_ip: Option<IpAddr>,
+    /// Advertise the address clients used to reach the webserver, when they used an IP rather than a name. Falls back to `public_ip`.
+    pub public_ip_from_host: bool,
+}
+
+/// A STUN or TURN server
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IceServer{
+    /// e.g.: "stun:stun.example.com:3478" or "turn:turn.example.com:3478?transport=udp"
+    pub urls: Vec<String>,
+    /// TURN only
+    pub username: String,
+    pub credential: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IceCandidates{
+    /// Whatever can be gathered: host addresses, plus what the ICE servers give
+    All,
+    /// Only the addresses of the server's own interfaces. Runs as an ICE lite agent.
+    Host,
+    /// Only addresses relayed through a TURN server
+    Relay,
+}
+impl Default for Config{
+    fn default()->Self{
+        Self{
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3000,
+            connect_timeout: 10,
+            channel_capacity: 64,
+            max_message_bytes: 2000,
+            message_burst: 5,
+            message_rate: 1.0,
+            slow_mode: 0,
+            repeat_window: 30,
+            history_replay: 50,
+            chatlog_capacity: 1000,
+            max_rooms: 50,
+            chatlog_dir: PathBuf::from("chatlogs"),
+            bans_path: PathBuf::from("bans.json"),
+            log_level: LevelFilter::Info,
+            ice_log_level: LevelFilter::Error,
+            host_passphrase: None,
+            usernames: DEFAULT_USERNAMES.iter().map(|x| x.to_string()).collect(),
+            ice_servers: vec![],
+            ice_candidates: IceCandidates::All,
+            ice_interfaces: vec![],
+            ice_interfaces_deny: vec![],
+            ice_udp_port: None,
+            ice_port_min: None,
+            ice_port_max: None,
+            public_ip: None,
+            public_ip_from_host: false,
+        }
+    }
+}
+impl Config{
+    pub fn connect_timeout(&self)->Duration{
+        Duration::from_secs(self.connect_timeout)
+    }
+    // Catches settings that would only fail later on
+    fn validate(&self)->Result<(), String>{
+        if self.connect_timeout == 0 { return Err("connect_timeout must be at least 1 second".into()); }
+        if self.channel_capacity == 0 { return Err("channel_capacity must be at least 1".into()); }
+        if self.max_message_bytes == 0 { return Err("max_message_bytes must be at least 1".into()); }
+        if self.message_burst == 0 { return Err("message_burst must be at least 1".into()); }
+        if !(self.message_rate > 0.0 && self.message_rate.is_finite()) { return Err("message_rate must be above 0".into()); }
+        if self.chatlog_capacity == 0 { return Err("chatlog_capacity must be at least 1".into()); }
+        if self.max_rooms == 0 { return Err("max_rooms must be at least 1".into()); }
+        if self.history_replay > self.chatlog_capacity { return Err("history_replay must be no more than chatlog_capacity".into()); }
+        if self.chatlog_dir.as_os_str().is_empty() { return Err("chatlog_dir must not be empty".into()); }
+        if self.bans_path.as_os_str().is_empty() { return Err("bans_path must not be empty".into()); }
+        if self.usernames.is_empty() { return Err("usernames must not be empty".into()); }
+        if self.usernames.iter().any(|x| x.trim().is_empty()) { return Err("usernames must not contain blank names".into()); }
+        if self.host_passphrase.as_ref().is_some_and(|x| x.is_empty()) { return Err("host_passphrase must not be empty. Leave it out to turn claiming host off.".into()); }
+        for server in &self.ice_servers {
+            if server.urls.is_empty() { return Err("ice_servers entries must have at least one url".into()); }
+            for url in &server.urls {
+                let Ok(parsed) = Url::parse_url(url) else { return Err(format!("ice_servers url {:?} is not a valid stun: or turn: url", url)); };
+                if is_turn(&parsed) && (server.username.is_empty() || server.credential.is_empty()) {
+                    return Err(format!("ice_servers url {:?} is a TURN server, so needs a username and credential", url));
+                }
+            }
+        }
+        let has_turn = self.ice_servers.iter().flat_map(|x| &x.urls).any(|x| Url::parse_url(x).is_ok_and(|x| is_turn(&x)));
+        if self.ice_candidates == IceCandidates::Relay && !has_turn { return Err("ice_candidates = relay needs a TURN server in ice_servers".into()); }
+        if self.ice_candidates == IceCandidates::Host && !self.ice_servers.is_empty() { return Err("ice_servers aren't used when ice_candidates = host. Remove them or use all.".into()); }
+        if let Some(x) = self.ice_interfaces.iter().find(|x| self.ice_interfaces_deny.contains(x)) { return Err(format!("interface {:?} is in both ice_interfaces and ice_interfaces_deny", x)); }
+        if self.ice_udp_port == Some(0) { return Err("ice_udp_port must not be 0. Leave it out to give each peer its own port.".into()); }
+        match (self.ice_port_min, self.ice_port_max) {
+            (None, None) => {},
+            (Some(min), Some(max)) => {
+                if min == 0 || min > max { return Err("ice_port_min must be at least 1, and no more than ice_port_max".into()); }
+                if self.ice_udp_port.is_some() { return Err("ice_port_min and ice_port_max aren't used with ice_udp_port. Set one or the other.".into()); }
+            }
+            _ => return Err("ice_port_min and ice_port_max must be set together".into()),
+        }
+        if self.public_ip.is_some_and(|x| x.is_unspecified()) { return Err("public_ip must be a specific address".into()); }
+        return Ok(());
+    }
+    /// Whether candidates can be gathered on the network interface called `name`
+    pub fn ice_interface_allowed(&self, name: &str)->bool{
+        let name = name.to_owned();
+        return (self.ice_interfaces.is_empty() || self.ice_interfaces.contains(&name)) && !self.ice_interfaces_deny.contains(&name);
+    }
+    /// The configuration as TOML, with the passphrase and TURN credentials hidden
+    pub fn describe(&self)->String{
+        let mut shown = self.clone();
+        if shown.host_passphrase.is_some() { shown.host_passphrase = Some("(hidden)".into()); }
+        for server in shown.ice_servers.iter_mut().filter(|x| !x.credential.is_empty()) { server.credential = "(hidden)".into(); }
+        return toml::to_string(&shown).unwrap_or_else(|e| {
+            warn!("Could not describe the configuration ({e})");
+            String::new()
+        });
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Hosts the chat server and its web client")]
+struct Args{
+    /// Config file [default: webrtc-lan.toml, if it exists]
+    #[arg(long, env = "WEBRTC_LAN_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "WEBRTC_LAN_HOST")]
+    host: Option<IpAddr>,
+    #[arg(long, env = "WEBRTC_LAN_PORT")]
+    port: Option<u16>,
+    /// Seconds
+    #[arg(long, env = "WEBRTC_LAN_CONNECT_TIMEOUT")]
+    connect_timeout: Option<u64>,
+    #[arg(long, env = "WEBRTC_LAN_CHANNEL_CAPACITY")]
+    channel_capacity: Option<usize>,
+    #[arg(long, env = "WEBRTC_LAN_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+    #[arg(long, env = "WEBRTC_LAN_ICE_LOG_LEVEL")]
+    ice_log_level: Option<LevelFilter>,
+    /// Bytes
+    #[arg(long, env = "WEBRTC_LAN_MAX_MESSAGE_BYTES")]
+    max_message_bytes: Option<usize>,
+    #[arg(long, env = "WEBRTC_LAN_MESSAGE_BURST")]
+    message_burst: Option<u32>,
+    /// Messages per second
+    #[arg(long, env = "WEBRTC_LAN_MESSAGE_RATE")]
+    message_rate: Option<f64>,
+    /// Seconds, 0 for off
+    #[arg(long, env = "WEBRTC_LAN_SLOW_MODE")]
+    slow_mode: Option<u64>,
+    /// Seconds, 0 for off
+    #[arg(long, env = "WEBRTC_LAN_REPEAT_WINDOW")]
+    repeat_window: Option<u64>,
+    /// Messages, 0 for off
+    #[arg(long, env = "WEBRTC_LAN_HISTORY_REPLAY")]
+    history_replay: Option<usize>,
+    /// Messages
+    #[arg(long, env = "WEBRTC_LAN_CHATLOG_CAPACITY")]
+    chatlog_capacity: Option<usize>,
+    /// Rooms, counting the default one
+    #[arg(long, env = "WEBRTC_LAN_MAX_ROOMS")]
+    max_rooms: Option<usize>,
+    #[arg(long, env = "WEBRTC_LAN_CHATLOG_DIR")]
+    chatlog_dir: Option<PathBuf>,
+    #[arg(long, env = "WEBRTC_LAN_BANS_PATH")]
+    bans_path: Option<PathBuf>,
+    #[arg(long, env = "WEBRTC_LAN_HOST_PASSPHRASE", hide_env_values = true)]
+    host_passphrase: Option<String>,
+    /// Comma separated
+    #[arg(long, env = "WEBRTC_LAN_USERNAMES", value_delimiter = ',')]
+    usernames: Option<Vec<String>>,
+    /// Comma separated STUN urls, replacing ice_servers. TURN servers need credentials, so are set in the config file.
+    #[arg(long, env = "WEBRTC_LAN_STUN_SERVERS", value_delimiter = ',')]
+    stun_servers: Option<Vec<String>>,
+    #[arg(long, env = "WEBRTC_LAN_ICE_CANDIDATES")]
+    ice_candidates: Option<IceCandidates>,
+    /// Comma separated interface names
+    #[arg(long, env = "WEBRTC_LAN_ICE_INTERFACES", value_delimiter = ',')]
+    ice_interfaces: Option<Vec<String>>,
+    /// Comma separated interface names
+    #[arg(long, env = "WEBRTC_LAN_ICE_INTERFACES_DENY", value_delimiter = ',')]
+    ice_interfaces_deny: Option<Vec<String>>,
+    #[arg(long, env = "WEBRTC_LAN_ICE_UDP_PORT")]
+    ice_udp_port: Option<u16>,
+    #[arg(long, env = "WEBRTC_LAN_ICE_PORT_MIN")]
+    ice_port_min: Option<u16>,
+    #[arg(long, env = "WEBRTC_LAN_ICE_PORT_MAX")]
+    ice_port_max: Option<u16>,
+    #[arg(long, env = "WEBRTC_LAN_PUBLIC_IP")]
+    public_ip: Option<IpAddr>,
+    #[arg(long, env = "WEBRTC_LAN_PUBLIC_IP_FROM_HOST")]
+    public_ip_from_host: Option<bool>,
+}
+
+/// Reads the configuration from the command line, environment and config file. Exits on `--help`.
+/// Also gives warnings about how it was set, to log once logging is up.
+pub fn load()->Result<(Config, Vec<String>), String>{
+    let args = Args::parse();
+    let mut warnings = vec![];
+    let mut config = match &args.config {
+        Some(path) => read(path)?,
+        None if std::path::Path::new(DEFAULT_PATH).exists() => read(&PathBuf::from(DEFAULT_PATH))?,
+        None => Config::default(),
+    };
+    if let Some(x) = args.host { config.host = x; }
+    if let Some(x) = args.port { config.port = x; }
+    if let Some(x) = args.connect_timeout { config.connect_timeout = x; }
+    if let Some(x) = args.channel_capacity { config.channel_capacity = x; }
+    if let Some(x) = args.log_level { config.log_level = x; }
+    if let Some(x) = args.ice_log_level { config.ice_log_level = x; }
+    if let Some(x) = args.max_message_bytes { config.max_message_bytes = x; }
+    if let Some(x) = args.message_burst { config.message_burst = x; }
+    if let Some(x) = args.message_rate { config.message_rate = x; }
+    if let Some(x) = args.slow_mode { config.slow_mode = x; }
+    if let Some(x) = args.repeat_window { config.repeat_window = x; }
+    if let Some(x) = args.history_replay { config.history_replay = x; }
+    if let Some(x) = args.chatlog_capacity { config.chatlog_capacity = x; }
+    if let Some(x) = args.max_rooms { config.max_rooms = x; }
+    if let Some(x) = args.chatlog_dir { config.chatlog_dir = x; }
+    if let Some(x) = args.bans_path { config.bans_path = x; }
+    if let Ok(x) = std::env::var(OLD_PASSPHRASE_VAR) {
+        if args.host_passphrase.is_none() {
+            warnings.push(format!("{OLD_PASSPHRASE_VAR} is deprecated. Set WEBRTC_LAN_HOST_PASSPHRASE instead."));
+            config.host_passphrase = Some(x);
+        } else {
+            warnings.push(format!("{OLD_PASSPHRASE_VAR} is deprecated, and ignored as the passphrase is set another way. Unset it."));
+        }
+    }
+    if let Some(x) = args.host_passphrase { config.host_passphrase = Some(x); }
+    if let Some(x) = args.usernames { config.usernames = x; }
+    if let Some(x) = args.stun_servers { config.ice_servers = vec![IceServer{ urls: x, ..Default::default() }]; }
+    if let Some(x) = args.ice_candidates { config.ice_candidates = x; }
+    if let Some(x) = args.ice_interfaces { config.ice_interfaces = x; }
+    if let Some(x) = args.ice_interfaces_deny { config.ice_interfaces_deny = x; }
+    if let Some(x) = args.ice_udp_port { config.ice_udp_port = Some(x); }
+    if let Some(x) = args.ice_port_min { config.ice_port_min = Some(x); }
+    if let Some(x) = args.ice_port_max { config.ice_port_max = Some(x); }
+    if let Some(x) = args.public_ip { config.public_ip = Some(x); }
+    if let Some(x) = args.public_ip_from_host { config.public_ip_from_host = x; }
+    config.validate()?;
+    return Ok((config, warnings));
+}
+
+fn is_turn(url: &Url)->bool{
+    return matches!(url.scheme, SchemeType::Turn | SchemeType::Turns);
+}
+
+fn read(path: &PathBuf)->Result<Config, String>{
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {} ({e})", path.display()))?;
+    return toml::from_str(&text).map_err(|e| format!("Could not parse {} ({e})", path.display()));
+}
+
+/// Makes `config` the one `get` returns. Only the first call has any effect.
+pub fn set(config: Config){
+    let _ = CONFIG.set(config);
+}
+
+/// The configuration in use. The defaults if none was set.
+pub fn get()->&'static Config{
+    CONFIG.get_or_init(Config::default)
+}
+
+const DEFAULT_USERNAMES: &[&str] = &["Abiu","Akebi","Ackee","African","American","Apple","Apricot","Aratiles","Araza","Avocado","Banana","Bilberry","Blackberry","Blackcurrant","Blueberry","Boysenberry","Breadfruit","Cactus","Canistel","Catmon","Cempedak","Cherimoya","Cherry","Chico","Citron","Cloudberry","Coco","Coconut","Crab","Cranberry","Currant","Damson","Date","Dragonfruit","Durian","Elderberry","Feijoa","Fig","Finger","Gac","Goji","Gooseberry","Grape","Raisin","Grapefruit","Grewia","Guava","Hala","Haws,","Honeyberry","Huckleberry","Jabuticaba","Jackfruit","Jambul","Japanese","Jostaberry","Jujube","Juniper","Kaffir","Kiwano","Kiwifruit","Kumquat","Lanzones","Lemon","Lime","Loganberry","Longan","Loquat","Lulo","Lychee","Magellan","Macopa","Mamey","Mamey","Mango","Mangosteen","Marionberry","Medlar","Melon","Cantaloupe","Galia","Honeydew","Mouse","Muskmelon","Watermelon","Miracle","Momordica","Monstera","Mulberry","Nance","Nectarine","Orange","Blood","Clementine","Mandarine","Tangerine","Papaya","Passionfruit","Pawpaw","Peach","Pear","Persimmon","Plantain","Plum","Prune","Pineapple","Pineberry","Plumcot","Pomegranate","Pomelo","Quince","Raspberry","Salmonberry","Rambutan","Redcurrant","Rose","Salal","Salak","Santol","Sapodilla","Sapote","Sarguelas","Satsuma","Sloe","Soursop","Star","Strawberry","Sugar","Suriname","Tamarillo","Tamarind","Tangelo","Tayberry","Thimbleberry","Ugli","White","Ximenia","Yuzu"];
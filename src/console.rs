@@ -0,0 +1,228 @@
+//! The host's command console, read from stdin.
+
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::{bans::{self, BanTarget}, chatfilter, chatroom::{system_msg, Lobby, ROOMS}, fi, names, packets::Role, shutdown, usersession::SessionId, webrtcsignalling};
+
+const HELP: &str = "\
+Commands:
+  /list                       Sessions in every room: id, name, address, connection, role. Then any still connecting.
+  /say <text>                 Posts a server message to every room
+  /kick <id|name> [reason]    Disconnects someone
+  /rename <id|name> <name>    Renames someone
+  /mute <id|name> [minutes]   Stops someone chatting or renaming themselves, for 10 minutes by default
+  /unmute <id|name>           Lets them again
+  /slowmode [seconds|off]     Shows or sets the time everyone but moderators and hosts has to wait between messages
+  /role <id|name> <role>      Makes someone a spectator, member, moderator or host
+  /ban <session|name|ip> <who> [reason]
+                              Bans a session (id or name), a name, or an address (ip, or someone's id or name),
+                              kicking whoever it covers
+  /bans                       Numbered list of bans
+  /unban <number>             Lifts a ban
+  /clear [room]               Wipes the chat log of a room, or of every room
+  /shutdown [reason]          Stops the server, telling everyone why";
+
+/// How long /mute lasts when no time is given
+const DEFAULT_MUTE_MINUTES: u64 = 10;
+
+/// Runs commands typed into the terminal until shutdown
+pub async fn cli(){
+    // tokio's stdin would hold up the runtime at exit while it waits for a line, so it gets its own thread
+    let (tx, mut rx) = mpsc::channel::<String>(8);
+    std::thread::spawn(move||{
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break; };
+            if tx.blocking_send(line).is_err() { break; }
+        }
+    });
+
+    loop{ tokio::select!{
+        line = rx.recv() => match line{
+            Some(line) => {
+                let output = run_command(&line).await;
+                if !output.is_empty() { println!("{}", output.trim_end()); }
+            }
+            None => { shutdown::requested().await; break; } // No stdin, wait it out
+        },
+        _ = shutdown::requested() => break,
+    }}
+}
+
+/// Runs a console command, returning what it has to say
+pub async fn run_command(line: &str)->String{
+    let mut out = String::new();
+    let o = &mut out;
+    let line = line.trim();
+    if line.is_empty() { return out; }
+    let line = line.strip_prefix('/').unwrap_or(line);
+    let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+    match command{
+        "list" => {
+            for room in ROOMS.all().await {
+                let members = room.members().await;
+                let _ = writeln!(o, "{} ({})", room.name, members.len());
+                for (p, user) in members {
+                    let address = user.read().await.address.clone();
+                    let _ = writeln!(o, "  {:>16}  {:<24} {:<22} {:?} {:?}", p.sid, p.name, address, p.link, p.role);
+                }
+            }
+            for (source, age) in webrtcsignalling::pending_offers() {
+                let _ = writeln!(o, "Waiting on {} to connect ({}s)", source, age.as_secs());
+            }
+        }
+        "say" => {
+            if args.is_empty() { return usage(command); }
+            for room in ROOMS.all().await {
+                room.send_message(system_msg(args.to_owned())).await;
+            }
+        }
+        "kick" => {
+            if args.is_empty() { return usage(command); }
+            let (who, reason) = args.split_once(char::is_whitespace).unwrap_or((args, "Kicked by the host"));
+            let Some((room, sid)) = find_member(who).await else { return format!("No one matches {}", who); };
+            room.kick(sid, reason.trim().to_owned()).await;
+        }
+        "rename" => {
+            let Some((who, name)) = args.split_once(char::is_whitespace) else { return usage(command); };
+            let Some((room, sid)) = find_member(who).await else { return format!("No one matches {}", who); };
+            match names::check(name, sid).await {
+                Ok(name) => room.rename(sid, name).await,
+                Err(result) => return format!("Can't rename them to {}: {}", name.trim(), names::refusal(result)),
+            }
+        }
+        "mute" => {
+            if args.is_empty() { return usage(command); }
+            let (who, minutes) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            let minutes = fi!(minutes.trim().is_empty(), Ok(DEFAULT_MUTE_MINUTES), minutes.trim().parse::<u64>());
+            let Ok(minutes) = minutes else { return usage(command); };
+            let Some((room, sid)) = find_member(who).await else { return format!("No one matches {}", who); };
+            room.set_muted(sid, Some(Duration::from_secs(minutes * 60))).await;
+        }
+        "unmute" => {
+            if args.is_empty() { return usage(command); }
+            let Some((room, sid)) = find_member(args).await else { return format!("No one matches {}", args); };
+            room.set_muted(sid, None).await;
+        }
+        "slowmode" => {
+            if args.is_empty() {
+                let slow = chatfilter::slow_mode();
+                return fi!(slow.is_zero(), "Slow mode is off".into(), format!("Slow mode is {} seconds", slow.as_secs()));
+            }
+            let seconds = fi!(args == "off", Ok(0), args.parse::<u64>());
+            let Ok(seconds) = seconds else { return usage(command); };
+            chatfilter::set_slow_mode(Duration::from_secs(seconds));
+            let announcement = fi!(seconds == 0, "Slow mode is off.".to_owned(), format!("Slow mode is on: one message every {} seconds.", seconds));
+            for room in ROOMS.all().await {
+                room.send_message(system_msg(announcement.clone())).await;
+            }
+        }
+        "role" => {
+            let Some((who, role)) = args.split_once(char::is_whitespace) else { return usage(command); };
+            let role = match role.trim().to_lowercase().as_str(){
+                "spectator" => Role::Spectator,
+                "member" => Role::Member,
+                "moderator" => Role::Moderator,
+                "host" => Role::Host,
+                _ => return usage(command),
+            };
+            let Some((room, sid)) = find_member(who).await else { return format!("No one matches {}", who); };
+            room.set_role(sid, role).await;
+        }
+        "ban" => {
+            let mut words = args.splitn(3, char::is_whitespace);
+            let (Some(kind), Some(who)) = (words.next(), words.next()) else { return usage(command); };
+            let reason = words.next().map(str::trim).filter(|x| !x.is_empty()).unwrap_or("Banned by the host");
+            let Some(target) = ban_target(kind, who).await else { return format!("Can't ban {} {}", kind, who); };
+            if !bans::ban(target, reason.to_owned()).await { return "Already banned".into(); }
+        }
+        "bans" => {
+            for (i, ban) in bans::list().iter().enumerate() {
+                let _ = writeln!(o, "  {:>3}  {:<32} {}", i, ban.target, ban.reason);
+            }
+        }
+        "unban" => {
+            let Ok(index) = args.parse::<usize>() else { return usage(command); };
+            if bans::unban(index).is_none() { return format!("There is no ban {}", index); }
+        }
+        "clear" => {
+            if args.is_empty() {
+                for room in ROOMS.all().await { room.clear_log().await; }
+            } else if let Some(room) = ROOMS.get(args).await {
+                room.clear_log().await;
+            } else {
+                return format!("There is no room called {}", args);
+            }
+        }
+        "shutdown" => shutdown::request(fi!(args.is_empty(), shutdown::DEFAULT_REASON, args)),
+        "help" => return HELP.into(),
+        _ => {
+            warn!("Unknown command: {}", line);
+            return HELP.into();
+        }
+    }
+    return out;
+}
+
+// The command's line from HELP, without its description
+fn usage(command: &str)->String{
+    let line = HELP.lines().map(str::trim_start).find(|x| x.split_whitespace().next() == Some(&format!("/{command}")));
+    let usage = line.and_then(|x| x.split("  ").next()).unwrap_or(command);
+    return format!("Usage: {}", usage.trim());
+}
+
+// What `/ban <kind> <who>` refers to
+async fn ban_target(kind: &str, who: &str)->Option<BanTarget>{
+    let member = find_member(who).await;
+    match kind{
+        "session" => match member{
+            Some((_, sid)) => return Some(BanTarget::Session(sid)),
+            None => return u64::from_str_radix(who, 16).ok().map(|x| BanTarget::Session(SessionId(x))),
+        },
+        "name" => return Some(BanTarget::Name(who.to_owned())),
+        "ip" => {
+            if let Ok(ip) = who.parse() { return Some(BanTarget::Ip(ip)); }
+            let (room, sid) = member?;
+            let user = room.members().await.into_iter().find(|x| x.0.sid == sid)?.1;
+            return user.read().await.ip().map(BanTarget::Ip);
+        }
+        _ => return None,
+    }
+}
+
+/// Someone by session id (hex) or name, and the room they're in
+pub async fn find_member(who: &str)->Option<(Arc<Lobby>, SessionId)>{
+    for room in ROOMS.all().await {
+        let found = room.participants().await.into_iter()
+            .find(|p| p.sid.to_string() == who || p.name.eq_ignore_ascii_case(who));
+        if let Some(p) = found { return Some((room, p.sid)); }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn run(line: &str)->String{
+        futures::executor::block_on(run_command(line))
+    }
+
+    #[test]
+    fn commands_missing_arguments_give_their_usage(){
+        assert_eq!(run("/say"), "Usage: /say <text>");
+        assert_eq!(run("kick  "), "Usage: /kick <id|name> [reason]");
+        assert_eq!(run("/mute"), "Usage: /mute <id|name> [minutes]");
+        assert_eq!(run("/unmute"), "Usage: /unmute <id|name>");
+        assert_eq!(run("/ban session"), "Usage: /ban <session|name|ip> <who> [reason]");
+        assert_eq!(run("/unban x"), "Usage: /unban <number>");
+    }
+
+    #[test]
+    fn unknown_commands_give_the_help(){
+        assert_eq!(run("/frobnicate"), HELP);
+    }
+}
@@ -0,0 +1,17 @@
+pub mod webserver;
+pub mod console;
+pub mod shutdown;
+pub mod config;
+pub mod commands;
+#[cfg(feature = "tui")]
+pub mod tui;
+mod webrtcsignalling;
+pub mod webrtcpeer;
+mod chatroom;
+mod chatlog;
+mod chatfilter;
+mod bans;
+mod names;
+pub mod packets;
+mod usersession;
+mod util;
@@ -1,35 +1,52 @@
-mod webserver;
-mod webrtcsignalling;
-mod webrtcpeer;
-mod chatroom;
-mod packets;
-mod usersession;
-mod util;
+use std::{io::IsTerminal, time::Duration};
 
-use log::{info, LevelFilter};
+use log::{info, warn};
 use tokio::join;
-use webrtcpeer::ClientConnection;
-use webserver::webserver_run;
+use webrtc_native_receiver::{config, console::cli, shutdown, webserver::webserver_run};
 
-pub const WEBSERVER_PORT: u16 = 3000;
+/// Longest we wait on sessions to close before exiting regardless
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main(){
+    let warnings = match config::load() {
+        Ok((x, warnings)) => { config::set(x); warnings },
+        Err(e) => { eprintln!("Invalid configuration: {e}"); std::process::exit(2); }
+    };
+    // The dashboard needs a terminal to draw in. Without one, fall back to the plain console.
+    let dashboard = cfg!(feature = "tui") && std::io::stdout().is_terminal();
+    init_logging(dashboard);
+    for warning in warnings { warn!("{}", warning); }
+
+    info!("Initialising with configuration:\n{}", config::get().describe());
+    tokio::spawn(shutdown::on_ctrl_c());
+    let _ = join!(
+        webserver_run(),
+        console(dashboard),
+    );
+    // Give everyone connected a chance to hear why, and have their connection closed properly
+    shutdown::sessions_finished(SHUTDOWN_DEADLINE).await;
+    shutdown::logs_written(SHUTDOWN_DEADLINE).await;
+    info!("Goodbye");
+}
+
+fn init_logging(dashboard: bool){
+    #[cfg(feature = "tui")]
+    if dashboard { return webrtc_native_receiver::tui::init_logger(); }
+    let _ = dashboard;
+    let config = config::get();
     colog::basic_builder()
-        .filter_level(LevelFilter::Info)
+        .filter_level(config.log_level)
         // .default_format()
         // .format_timestamp(None)
         // .format_module_path(true)
-        .filter_module("webrtc_ice", LevelFilter::Error)
+        .filter_module("webrtc_ice", config.ice_log_level)
         .init();
-
-    info!("Initialising");
-    let _ = join!(
-        webserver_run(WEBSERVER_PORT),
-        cli(),
-    );
 }
 
-pub async fn cli(){
-
-}
\ No newline at end of file
+async fn console(dashboard: bool){
+    #[cfg(feature = "tui")]
+    if dashboard { return webrtc_native_receiver::tui::run().await; }
+    let _ = dashboard;
+    cli().await;
+}
@@ -0,0 +1,197 @@
+//! Username validation. Every name a session takes goes through `check`.
+
+use log::warn;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{bans, chatroom::ROOMS, fi, packets::NameResult, usersession::{SessionId, UserSession}};
+
+/// Longest name allowed, in characters
+pub const MAX_NAME_LEN: usize = 24;
+
+/// Names nobody can take, as they would pass for the server or staff. Compared by `skeleton`.
+const RESERVED: &[&str] = &["server", "system", "host", "admin", "administrator", "moderator", "mod", "everyone", "here"];
+
+/// Default names tried for a new session before settling on one regardless
+const DEFAULT_NAME_TRIES: u64 = 1000;
+
+/// Cleans up `name` and checks it can be used by session `sid`. Gives the name to use, or why it can't be.
+pub async fn check(name: &str, sid: SessionId)->Result<String, NameResult>{
+    let name = check_against(name, &taken(sid).await)?;
+    if bans::name_banned(&name).is_some() { return Err(NameResult::Banned); }
+    return Ok(name);
+}
+
+/// A name for the new session `sid` that passes `check`. One of the configured names followed by 3 digits.
+pub async fn default_name(sid: SessionId)->String{
+    let taken = taken(sid).await;
+    // Starts from the one the id picks, moving on through the digits then the names
+    for n in 0..DEFAULT_NAME_TRIES {
+        let name = UserSession::get_username_for_id(sid.0.wrapping_add(n));
+        if let Ok(name) = check_against(&name, &taken) {
+            if bans::name_banned(&name).is_none() { return name; }
+        }
+    }
+    let name = UserSession::get_username_for_id(sid.0);
+    warn!("No free default name for session {}. Giving it {} regardless.", sid, name);
+    return name;
+}
+
+// Skeletons of everyone else's names
+async fn taken(sid: SessionId)->Vec<[String; 2]>{
+    let mut taken = vec![];
+    for room in ROOMS.all().await {
+        taken.extend(room.members().await.into_iter().filter(|(p, _)| p.sid != sid).map(|(p, _)| skeletons(&p.name)));
+    }
+    return taken;
+}
+
+// What `check` does short of bans, given the skeletons of names already taken
+fn check_against(name: &str, taken: &[[String; 2]])->Result<String, NameResult>{
+    let name = clean(name);
+    if name.is_empty() { return Err(NameResult::Empty); }
+    if name.chars().count() > MAX_NAME_LEN { return Err(NameResult::TooLong); }
+    // In capitals too, so an l passing for the I in "ADMIN" is caught
+    if RESERVED.iter().any(|x| confusable(x, &name) || confusable(&x.to_uppercase(), &name)) { return Err(NameResult::Reserved); }
+    let skel = skeletons(&name);
+    if taken.iter().any(|x| x.iter().any(|x| skel.contains(x))) { return Err(NameResult::Taken); }
+    return Ok(name);
+}
+
+/// Why a name was refused, for the host
+pub fn refusal(result: NameResult)->&'static str{
+    match result{
+        NameResult::Accepted => "it was accepted",
+        NameResult::Empty => "it's empty",
+        NameResult::TooLong => "it's too long",
+        NameResult::Reserved => "it's reserved",
+        NameResult::Taken => "someone else has it, or one like it",
+        NameResult::Banned => "it's banned",
+        NameResult::Muted => "they're muted",
+        NameResult::NotAllowed => "spectators can't change their name",
+        NameResult::RateLimited => "they're doing that too quickly",
+    }
+}
+
+/// NFC normalises, drops control and invisible formatting characters, and collapses whitespace
+pub fn clean(name: &str)->String{
+    let kept: String = name.nfc().filter(|c| !c.is_control() && !invisible(*c)).collect();
+    return kept.split_whitespace().collect::<Vec<_>>().join(" ");
+}
+
+/// Bidi overrides and isolates, and zero width characters. They let text look like something it isn't.
+pub fn invisible(c: char)->bool{
+    matches!(c, '\u{061C}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2069}' | '\u{FEFF}')
+}
+
+/// Whether two names could be mistaken for each other
+pub fn confusable(a: &str, b: &str)->bool{
+    let b = skeletons(b);
+    return skeletons(a).iter().any(|x| b.contains(x));
+}
+
+/// What a name looks like, ignoring case, accents, spacing and common lookalike characters.
+/// A capital I passes for either an l or an i, so there are two: one reading it each way. Lowercase i has its dot, so "Ali" and "All" are told apart.
+/// Two names sharing a skeleton could be mistaken for each other.
+pub fn skeletons(name: &str)->[String; 2]{
+    return ['l', 'i'].map(|capital_i| {
+        let mapped: String = name.nfkd()
+            .filter(|c| !unicode_normalization::char::is_combining_mark(*c) && !c.is_whitespace() && !matches!(c, '_' | '-' | '.'))
+            .map(lookalike)
+            .map(|c| fi!(c == 'I', capital_i, c))
+            .flat_map(char::to_lowercase)
+            .map(lookalike)
+            .collect();
+        mapped.replace("rn", "m").replace("vv", "w")
+    });
+}
+
+// The latin letter a character is easily mistaken for. Capital I is left for `skeletons` to read both ways.
+fn lookalike(c: char)->char{
+    match c{
+        '0' | 'Ο' | 'ο' | 'О' | 'о' => 'o',
+        'Ι' | 'І' | 'Ӏ' => 'I',
+        '1' | '|' => 'l',
+        'ı' | 'ι' | 'і' => 'i',
+        '3' => 'e',
+        '5' => 's',
+        '@' | 'Α' | 'α' | 'А' | 'а' => 'a',
+        '$' | 'Ѕ' | 'ѕ' => 's',
+        'Β' | 'В' => 'b',
+        'Ε' | 'Е' | 'е' => 'e',
+        'Η' | 'Н' | 'һ' => 'h',
+        'Κ' | 'К' | 'κ' => 'k',
+        'Μ' | 'М' => 'm',
+        'Ν' | 'ν' => 'v',
+        'Ρ' | 'ρ' | 'Р' | 'р' => 'p',
+        'С' | 'с' | 'ϲ' => 'c',
+        'Τ' | 'Т' => 't',
+        'Χ' | 'χ' | 'Х' | 'х' => 'x',
+        'Υ' | 'У' | 'у' => 'y',
+        'Ζ' => 'z',
+        'Ј' | 'ј' => 'j',
+        'ԁ' => 'd',
+        'ԛ' => 'q',
+        'ѡ' | 'ԝ' => 'w',
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn taken(names: &[&str])->Vec<[String; 2]>{
+        names.iter().map(|x| skeletons(x)).collect()
+    }
+
+    #[test]
+    fn skeletons_catch_lookalikes(){
+        assert!(confusable("Admin", "ADMIN"));
+        assert!(confusable("ADMIN", "admln"));
+        assert!(confusable("Bob_01", "bob 0l"));
+        assert!(confusable("Café", "cafe"));
+        assert!(confusable("modern", "modem"));
+        assert!(confusable("Раypal", "paypal")); // Cyrillic Р and а
+        assert!(confusable("IIama", "llama"));
+        assert!(!confusable("Fig", "Date"));
+    }
+
+    #[test]
+    fn skeletons_keep_lowercase_i(){
+        assert!(!confusable("Ali", "All"));
+        assert!(!confusable("mike", "mlke"));
+        // A capital I could be either
+        assert!(confusable("ALI", "Ali"));
+        assert!(confusable("ALI", "All"));
+        assert_eq!(skeletons("Ali"), ["ali".to_owned(), "ali".to_owned()]);
+        assert_eq!(skeletons("ALI"), ["all".to_owned(), "ali".to_owned()]);
+    }
+
+    #[test]
+    fn check_cleans_and_limits(){
+        assert_eq!(check_against("  Fig \u{200B} Tree\t", &[]), Ok("Fig Tree".to_owned()));
+        assert_eq!(check_against(" \u{202E} ", &[]), Err(NameResult::Empty));
+        assert_eq!(check_against(&"x".repeat(MAX_NAME_LEN), &[]).map(|x| x.len()), Ok(MAX_NAME_LEN));
+        assert_eq!(check_against(&"x".repeat(MAX_NAME_LEN + 1), &[]), Err(NameResult::TooLong));
+    }
+
+    #[test]
+    fn check_refuses_reserved(){
+        assert_eq!(check_against("Server", &[]), Err(NameResult::Reserved));
+        assert_eq!(check_against("ADMIN", &[]), Err(NameResult::Reserved));
+        assert_eq!(check_against("adm1n", &[]), Err(NameResult::Reserved));
+        assert_eq!(check_against("ADMlN", &[]), Err(NameResult::Reserved));
+        assert_eq!(check_against("h0st", &[]), Err(NameResult::Reserved));
+        assert_eq!(check_against("Hosting", &[]), Ok("Hosting".to_owned()));
+    }
+
+    #[test]
+    fn check_refuses_duplicates(){
+        let others = taken(&["Apple123", "Ali"]);
+        assert_eq!(check_against("apple123", &others), Err(NameResult::Taken));
+        assert_eq!(check_against("App1e l23", &others), Err(NameResult::Taken));
+        assert_eq!(check_against("ALI", &others), Err(NameResult::Taken));
+        assert_eq!(check_against("All", &others), Ok("All".to_owned()));
+        assert_eq!(check_against("Apple124", &others), Ok("Apple124".to_owned()));
+    }
+}
@@ -3,62 +3,231 @@
 use core::str;
 use std::io::{Cursor, Write};
 
-use derive_more::{derive::Debug, From};
+use derive_more::{derive::Debug, BitAnd, BitOr, From};
 use derive_new::new;
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
+use packet_derive::{Packet, PacketSet, Record, WireEnum};
+use serde::{Deserialize, Serialize};
 
-use crate::usersession::SessionId;
+use crate::usersession::{ResumeToken, SessionId};
 
-// TODO: Improve networking ergonomics
-// pub poison is big sad
-// lots of boilerplates and results
-// and I still need to derive MORE
+pub mod typescript;
 
-// Network representation of packets
-#[derive(FromPrimitive)]
-enum PktC2Sid{
-    Hello = 0,
-    SendMsg = 1,
-    SetName = 2,
-    Goodbye = 3,
-    Buttons = 4,
+// Adding a packet:
+// 1. Define the struct and #[derive(Packet)] with a unique #[packet(id = N)] for its direction
+// 2. Add a variant wrapping it to PktC2S or PktS2C
+// 3. Run `cargo run --bin gen-packets` to regenerate the web client's copy
+// The id enums (PktC2Sid, PktS2Cid), encoding, decoding and dispatch are generated from that.
+// Structs used as fields (e.g. in a Vec) #[derive(Record)], and fieldless enums #[derive(WireEnum)].
+
+/// Bump whenever the wire format changes incompatibly.
+/// Clients outside of MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION are turned away in Hello.
+pub const PROTOCOL_VERSION: u32 = 7;
+pub const MIN_PROTOCOL_VERSION: u32 = 7;
+
+/// Optional protocol features. Both sides send what they support and use the intersection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, BitAnd, BitOr)]
+#[debug("{_0:#x}")]
+pub struct Capabilities(pub u32);
+impl Capabilities{
+    pub const NONE: Self = Self(0);
+    /// The wave button, sent over the unreliable channel
+    pub const BUTTONS: Self = Self(1 << 0);
+    /// Recent chat is replayed on joining, older chat can be fetched
+    pub const HISTORY: Self = Self(1 << 1);
+    /// Rooms other than the default one can be listed, created and joined
+    pub const ROOMS: Self = Self(1 << 2);
+
+    /// Everything this server can do
+    pub const SERVER: Self = Self(Self::BUTTONS.0 | Self::HISTORY.0 | Self::ROOMS.0);
+    /// Names for the web client's code generator
+    pub const ALL: &'static [(&'static str, Self)] = &[
+        ("Buttons", Self::BUTTONS),
+        ("History", Self::HISTORY),
+        ("Rooms", Self::ROOMS),
+    ];
+
+    pub fn contains(self, other: Self)->bool{
+        self.0 & other.0 == other.0
+    }
+}
+
+/// What sort of chat message it is, for the client to display it appropriately
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, WireEnum)]
+pub enum MsgKind{
+    /// Something a participant said
+    User = 0,
+    /// From the server: joins, leaves, renames...
+    System = 1,
+    /// A participant describing what they're doing (/me)
+    Action = 2,
+}
+
+/// A chat message, as logged and as sent to clients
+#[derive(Clone, Debug, Record, Serialize, Deserialize)]
+pub struct ChatMsg{
+    /// Unique across the server
+    pub id: u64,
+    /// Unix time in milliseconds, by the server's clock
+    pub time: u64,
+    /// SessionId::SERVER for system messages
+    pub author: SessionId,
+    pub kind: MsgKind,
+    /// The author's name at the time
+    pub name: String,
+    pub text: String,
 }
 
+/// How well a participant is connected
 #[repr(u8)]
-enum PktS2Cid{
-    HelloReply = 0,
-    ReceiveMsg = 1,
-    SetNameReply = 2,
-    LobbyInfo = 3,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, WireEnum)]
+pub enum Link{
+    Good = 0,
+    /// The connection is interrupted, but may recover by itself
+    Unstable = 1,
+    /// The connection is gone. The session is held in case the client comes back.
+    Lost = 2,
+}
+
+/// What a participant is allowed to do in their room
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, WireEnum)]
+pub enum Role{
+    /// Can only watch: no chatting, renaming or buttons
+    Spectator = 0,
+    #[default]
+    Member = 1,
+    Moderator = 2,
+    Host = 3,
+}
+
+/// Whether a name change went through, and why not if it didn't
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, WireEnum)]
+pub enum NameResult{
+    Accepted = 0,
+    /// Nothing left once cleaned up
+    Empty = 1,
+    TooLong = 2,
+    /// Looks like the server or staff
+    Reserved = 3,
+    /// Someone else has it, or one that looks like it
+    Taken = 4,
+    Banned = 5,
+    Muted = 6,
+    /// Spectators can't change their name
+    NotAllowed = 7,
+    /// Renaming counts against the chat limits, and they were hit
+    RateLimited = 8,
+}
+
+/// Why a Notice was sent
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, WireEnum)]
+pub enum NoticeKind{
+    /// The message was over the size limit
+    TooLong = 0,
+    /// Too many messages too quickly
+    RateLimited = 1,
+    /// Too soon after the last message while slow mode is on
+    SlowMode = 2,
+    /// The same as a recent message
+    Repeated = 3,
+    Muted = 4,
+    /// Spectators can't chat
+    NotAllowed = 5,
+    /// Whoever a whisper was for has left, or isn't connected
+    NoRecipient = 6,
+    /// What a chat command had to say
+    Command = 7,
+}
+
+/// One entry of a room's participant list
+#[derive(new, Clone, Debug, Record)]
+pub struct Participant{
+    pub sid: SessionId,
+    pub name: String,
+    /// Is the wave button held
+    pub hand: bool,
+    pub link: Link,
+    pub role: Role,
 }
 
 // In memory representation of a packet
-#[derive(From, Debug)]
+#[derive(From, Debug, PacketSet)]
 pub enum PktC2S{
     Hello(PktC2S_Hello),
     SendMsg(PktC2S_SendMsg),
     SetName(PktC2S_SetName),
     Goodbye(PktC2S_Goodbye),
-    Buttons(PktC2S_Buttons)
+    Buttons(PktC2S_Buttons),
+    FetchHistory(PktC2S_FetchHistory),
+    ListRooms(PktC2S_ListRooms),
+    JoinRoom(PktC2S_JoinRoom),
+    CreateRoom(PktC2S_CreateRoom),
+    ClaimHost(PktC2S_ClaimHost),
+    Whisper(PktC2S_Whisper),
 }
-#[derive(Debug)] pub struct PktC2S_Hello{pub sid: Option<SessionId>}
-#[derive(Debug)] pub struct PktC2S_SendMsg{pub msg: String}
-#[derive(Debug)] pub struct PktC2S_SetName{pub name: String}
-#[derive(Debug)] pub struct PktC2S_Goodbye{}
-#[derive(Debug)] pub struct PktC2S_Buttons{pub pressed: bool}
+#[derive(Debug, Packet)] #[packet(id = 0)] pub struct PktC2S_Hello{pub version: u32, pub caps: Capabilities, pub resume: Option<ResumeToken>}
+#[derive(Debug, Packet)] #[packet(id = 1)] pub struct PktC2S_SendMsg{#[packet(exhaustive)] pub msg: String}
+#[derive(Debug, Packet)] #[packet(id = 2)] pub struct PktC2S_SetName{#[packet(exhaustive)] pub name: String}
+#[derive(Debug, Packet)] #[packet(id = 3)] pub struct PktC2S_Goodbye{}
+#[derive(Debug, Packet)] #[packet(id = 4)] pub struct PktC2S_Buttons{pub pressed: bool}
+/// Asks for up to `count` messages logged before message number `before`
+#[derive(Debug, Packet)] #[packet(id = 5)] pub struct PktC2S_FetchHistory{pub before: u64, pub count: u32}
+#[derive(Debug, Packet)] #[packet(id = 6)] pub struct PktC2S_ListRooms{}
+/// Moves to an existing room
+#[derive(Debug, Packet)] #[packet(id = 7)] pub struct PktC2S_JoinRoom{#[packet(exhaustive)] pub name: String}
+/// Moves to a room, creating it if needed
+#[derive(Debug, Packet)] #[packet(id = 8)] pub struct PktC2S_CreateRoom{#[packet(exhaustive)] pub name: String}
+/// Becomes host by giving the server's host passphrase
+#[derive(derive_more::Debug, Packet)] #[packet(id = 9)] #[debug("PktC2S_ClaimHost(..)")] pub struct PktC2S_ClaimHost{#[packet(exhaustive)] pub passphrase: String}
+/// A direct message to someone in the same room. Kept out of the logs.
+#[derive(derive_more::Debug, Packet)] #[packet(id = 10)] #[debug("PktC2S_Whisper({target}, ..)")] pub struct PktC2S_Whisper{pub target: SessionId, #[packet(exhaustive)] pub msg: String}
 
-#[derive(From)]
-enum PktS2C{
+#[allow(dead_code)] // The server only sends these, so the enum itself just holds the S2C id space
+#[derive(From, Debug, PacketSet)]
+pub enum PktS2C{
     HelloReply(PktS2C_HelloReply),
     ReceiveMsg(PktS2C_ReceiveMsg),
     SetNameReply(PktS2C_SetNameReply),
-    LobbyInfo(PktS2C_LobbyInfo)
+    LobbyInfo(PktS2C_LobbyInfo),
+    HelloReject(PktS2C_HelloReject),
+    ChatHistory(PktS2C_ChatHistory),
+    RoomList(PktS2C_RoomList),
+    RoomJoined(PktS2C_RoomJoined),
+    ParticipantJoined(PktS2C_ParticipantJoined),
+    ParticipantUpdated(PktS2C_ParticipantUpdated),
+    ParticipantLeft(PktS2C_ParticipantLeft),
+    ServerClosing(PktS2C_ServerClosing),
+    Kicked(PktS2C_Kicked),
+    Notice(PktS2C_Notice),
+    Whisper(PktS2C_Whisper),
 }
-#[derive(new)] pub struct PktS2C_HelloReply{sid: SessionId, username: String}
-#[derive(new)] pub struct PktS2C_ReceiveMsg{msg: String}
-#[derive(new)] pub struct PktS2C_SetNameReply{name: String}
-#[derive(new)] pub struct PktS2C_LobbyInfo{users: Vec<String>}
+#[derive(new, Debug, Packet)] #[packet(id = 0)] pub struct PktS2C_HelloReply{pub sid: SessionId, pub token: ResumeToken, pub version: u32, pub caps: Capabilities, #[packet(exhaustive)] pub username: String}
+#[derive(new, Debug, Packet)] #[packet(id = 1)] pub struct PktS2C_ReceiveMsg{pub msg: ChatMsg}
+#[derive(new, Debug, Packet)] #[packet(id = 2)] pub struct PktS2C_SetNameReply{pub result: NameResult, pub name: String}
+/// Everyone in the room. Sent on joining it, after which the Participant* deltas keep it up to date.
+#[derive(new, Debug, Packet)] #[packet(id = 3)] pub struct PktS2C_LobbyInfo{pub users: Vec<Participant>}
+/// The connection is closed after sending this
+#[derive(new, Debug, Packet)] #[packet(id = 4)] pub struct PktS2C_HelloReject{pub version: u32, #[packet(exhaustive)] pub reason: String}
+/// A run of logged messages, oldest first. `first` is the number of msgs[0] in the log, `more` is set if there are older ones.
+#[derive(new, Debug, Packet)] #[packet(id = 5)] pub struct PktS2C_ChatHistory{pub first: u64, pub more: bool, pub msgs: Vec<ChatMsg>}
+#[derive(new, Debug, Packet)] #[packet(id = 6)] pub struct PktS2C_RoomList{pub rooms: Vec<String>}
+/// Sent whenever the client is put into a room, before the room's history
+#[derive(new, Debug, Packet)] #[packet(id = 7)] pub struct PktS2C_RoomJoined{#[packet(exhaustive)] pub name: String}
+#[derive(new, Debug, Packet)] #[packet(id = 8)] pub struct PktS2C_ParticipantJoined{pub who: Participant}
+/// Replaces the participant's entry
+#[derive(new, Debug, Packet)] #[packet(id = 9)] pub struct PktS2C_ParticipantUpdated{pub who: Participant}
+#[derive(new, Debug, Packet)] #[packet(id = 10)] pub struct PktS2C_ParticipantLeft{pub sid: SessionId}
+/// Sent to everyone just before the server closes their connection on the way out
+#[derive(new, Debug, Packet)] #[packet(id = 11)] pub struct PktS2C_ServerClosing{#[packet(exhaustive)] pub reason: String}
+/// The client was removed by the host (kicked or banned). The connection is closed after sending this.
+#[derive(new, Debug, Packet)] #[packet(id = 12)] pub struct PktS2C_Kicked{#[packet(exhaustive)] pub reason: String}
+/// For this client only: why a message it sent was refused, or what a chat command had to say
+#[derive(new, Debug, Packet)] #[packet(id = 13)] pub struct PktS2C_Notice{pub kind: NoticeKind, #[packet(exhaustive)] pub text: String}
+/// A direct message, delivered to whoever it's for and echoed back to its author
+#[derive(new, Debug, Packet)] #[packet(id = 14)] pub struct PktS2C_Whisper{pub to: SessionId, pub msg: ChatMsg}
 
 // Encoding and decoding traits
 pub trait Encode{
@@ -67,6 +236,44 @@ pub trait Encode{
 trait Decode{
     fn decode(src: &mut Decoder) -> Result<Self, ()> where Self: Sized;
 }
+/// Implemented by `#[derive(Packet)]`
+trait Packet{
+    const ID: u8;
+    fn fields() -> Vec<(&'static str, WireType)>;
+}
+/// A type that can be used as a packet field
+trait Wire: Sized{
+    fn put(&self, enc: &mut Encoder);
+    fn get(src: &mut Decoder) -> R<Self>;
+    fn wire_type() -> WireType;
+}
+
+// Packet layouts, as seen by the web client's code generator
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WireType{
+    U8,
+    Bool,
+    UVarint,
+    /// 8 bytes, little endian. The web client only keeps 53 bits.
+    U64,
+    Str,
+    ExhaustiveStr,
+    SessionId,
+    /// Fixed length byte string
+    Bytes(usize),
+    Arr(Box<WireType>),
+    Opt(Box<WireType>),
+    /// A `#[derive(Record)]` struct, by name and field layout
+    Record(&'static str, Vec<(&'static str, WireType)>),
+    /// A `#[derive(WireEnum)]` enum, by name and variants. Travels as a u8.
+    Enum(&'static str, Vec<(&'static str, u8)>),
+}
+#[derive(Debug)]
+pub struct PacketSchema{
+    pub name: &'static str,
+    pub id: u8,
+    pub fields: Vec<(&'static str, WireType)>,
+}
 
 // Helper reader and writer classes
 #[derive(new)]
@@ -132,12 +339,16 @@ impl Decoder{
     }
     pub fn get_arr<F: Fn(&mut Self)->R<T>, T>(&mut self, reader: F)->R<Vec<T>>{
         let len = self.get_uvarint()? as usize;
-        let mut vec = Vec::with_capacity(len);
+        // The length is the client's word. No more than what's left can really be there.
+        let mut vec = Vec::with_capacity(len.min(self.rem()));
         for _ in 0..len{
             vec.push(reader(self)?);
         }
         return Ok(vec);
     }
+    pub fn get_u64(&mut self)->R<u64>{
+        return self.get_bytes_const::<8>().map(u64::from_le_bytes);
+    }
     pub fn get_sessionid(&mut self)->R<SessionId>{
         return self.get_bytes_const::<8>().map(|x| SessionId(u64::from_le_bytes(x)));
     }
@@ -149,9 +360,6 @@ struct Encoder{
     buf: Cursor<Vec<u8>>,
 }
 impl Encoder{
-    fn with_capacity(cap: usize)->Self{
-        Self { buf: Cursor::new(Vec::with_capacity(cap)) }
-    }
     fn consume(self) -> Vec<u8>{
         self.buf.into_inner()
     }
@@ -179,92 +387,151 @@ impl Encoder{
     fn append_exhaustive_str(&mut self, dat: &str){
         self.append_bytes(dat.as_bytes());
     }
+    fn append_u64(&mut self, dat: u64){
+        self.append_bytes(&dat.to_le_bytes());
+    }
     fn append_sessionid(&mut self, dat: SessionId){
         self.append_bytes(&dat.0.to_le_bytes());
     }
 }
 
-// Implementing encode and decode
-impl Decode for PktC2S_Hello{
-    fn decode(src: &mut Decoder) -> Result<Self, ()> {
-        let sid = src.get_sessionid().ok();
-        Ok(Self { sid })
-    }
+// Field types
+impl Wire for u8{
+    fn put(&self, enc: &mut Encoder){ enc.append_u8(*self) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_u8() }
+    fn wire_type() -> WireType{ WireType::U8 }
 }
-impl Decode for PktC2S_SendMsg{
-    fn decode(src: &mut Decoder) -> Result<Self, ()> {
-        let msg = src.get_exhaustive_str();
-        Ok(Self { msg })
-    }
+impl Wire for bool{
+    fn put(&self, enc: &mut Encoder){ enc.append_u8(*self as u8) }
+    fn get(src: &mut Decoder) -> R<Self>{ Ok(src.get_u8()? != 0) }
+    fn wire_type() -> WireType{ WireType::Bool }
 }
-impl Decode for PktC2S_SetName{
-    fn decode(src: &mut Decoder) -> Result<Self, ()> {
-        let name = src.get_exhaustive_str();
-        Ok(Self { name })
-    }
+// u32s travel as uvarints, so they are really 28 bits wide.
+impl Wire for u32{
+    fn put(&self, enc: &mut Encoder){ enc.append_uvarint(*self) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_uvarint() }
+    fn wire_type() -> WireType{ WireType::UVarint }
+}
+impl Wire for u64{
+    fn put(&self, enc: &mut Encoder){ enc.append_u64(*self) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_u64() }
+    fn wire_type() -> WireType{ WireType::U64 }
+}
+impl Wire for String{
+    fn put(&self, enc: &mut Encoder){ enc.append_str(self) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_str() }
+    fn wire_type() -> WireType{ WireType::Str }
 }
-impl Decode for PktC2S_Goodbye{
-    fn decode(src: &mut Decoder) -> Result<Self, ()> {
-        Ok(Self{})
+impl Wire for SessionId{
+    fn put(&self, enc: &mut Encoder){ enc.append_sessionid(*self) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_sessionid() }
+    fn wire_type() -> WireType{ WireType::SessionId }
+}
+impl Wire for ResumeToken{
+    fn put(&self, enc: &mut Encoder){ enc.append_bytes(&self.0) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_bytes_const().map(Self) }
+    fn wire_type() -> WireType{ WireType::Bytes(16) }
+}
+impl Wire for Capabilities{
+    fn put(&self, enc: &mut Encoder){ enc.append_uvarint(self.0) }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_uvarint().map(Self) }
+    fn wire_type() -> WireType{ WireType::UVarint }
+}
+impl<T: Wire> Wire for Vec<T>{
+    fn put(&self, enc: &mut Encoder){
+        enc.append_uvarint(self.len() as u32);
+        for x in self{ x.put(enc); }
     }
+    fn get(src: &mut Decoder) -> R<Self>{ src.get_arr(T::get) }
+    fn wire_type() -> WireType{ WireType::Arr(T::wire_type().into()) }
 }
-impl Decode for PktC2S_Buttons{
-    fn decode(src: &mut Decoder) -> Result<Self, ()> {
-        let pressed = src.get_u8()? != 0;
-        Ok(Self { pressed })
+// Optional fields are only valid at the end of a packet: absent if the packet has been exhausted. `#[derive(Packet)]` holds them to that.
+impl<T: Wire> Wire for Option<T>{
+    fn put(&self, enc: &mut Encoder){
+        if let Some(x) = self { x.put(enc); }
+    }
+    fn get(src: &mut Decoder) -> R<Self>{
+        if src.rem() == 0 { return Ok(None); }
+        return T::get(src).map(Some);
     }
+    fn wire_type() -> WireType{ WireType::Opt(T::wire_type().into()) }
 }
-//
 
-impl Encode for PktS2C_HelloReply{
-    fn encode(self) -> Vec<u8> {
-        let mut enc = Encoder::new();
-        enc.append_u8(PktS2Cid::HelloReply as u8);
-        enc.append_sessionid(self.sid);
-        enc.append_exhaustive_str(&self.username);
-        return enc.consume();
-    }
+// Universal decode function
+pub(crate) fn decode(src: Vec<u8>) -> R<PktC2S>{
+    return PktC2S::decode(&mut Decoder::new(src));
 }
-impl Encode for PktS2C_ReceiveMsg{
-    fn encode(self) -> Vec<u8> {
-        let mut enc = Encoder::new();
-        enc.append_u8(PktS2Cid::ReceiveMsg as u8);
-        enc.append_str(&self.msg);
-        return enc.consume();
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn msg(text: &str)->ChatMsg{
+        ChatMsg{ id: 7, time: 1_700_000_000_000, author: SessionId(0x0123_4567_89ab_cdef), kind: MsgKind::Action, name: "Fig".into(), text: text.into() }
     }
-}
-impl Encode for PktS2C_SetNameReply{
-    fn encode(self) -> Vec<u8> {
-        let mut enc = Encoder::new();
-        enc.append_u8(PktS2Cid::SetNameReply as u8);
-        enc.append_str(&self.name);
-        return enc.consume();
+    fn c2s(bytes: Vec<u8>)->R<PktC2S>{
+        return PktC2S::decode(&mut Decoder::new(bytes));
     }
-}
-impl Encode for PktS2C_LobbyInfo{
-    fn encode(self) -> Vec<u8> {
-        let mut enc = Encoder::new();
-        enc.append_u8(PktS2Cid::LobbyInfo as u8);
-        enc.append_uvarint(self.users.len() as u32);
-        for u in self.users{
-            enc.append_str(&u);
+    fn s2c(bytes: Vec<u8>)->R<PktS2C>{
+        return PktS2C::decode(&mut Decoder::new(bytes));
+    }
+
+    #[test]
+    fn c2s_round_trip(){
+        let token = ResumeToken([9; 16]);
+        let Ok(PktC2S::Hello(p)) = c2s(PktC2S_Hello{ version: 300, caps: Capabilities(5), resume: Some(token) }.encode()) else { panic!() };
+        assert_eq!((p.version, p.caps, p.resume), (300, Capabilities(5), Some(token)));
+        let Ok(PktC2S::Hello(p)) = c2s(PktC2S_Hello{ version: 1, caps: Capabilities::NONE, resume: None }.encode()) else { panic!() };
+        assert_eq!(p.resume, None);
+        let Ok(PktC2S::SendMsg(p)) = c2s(PktC2S_SendMsg{ msg: "héllo there".into() }.encode()) else { panic!() };
+        assert_eq!(p.msg, "héllo there");
+        let Ok(PktC2S::FetchHistory(p)) = c2s(PktC2S_FetchHistory{ before: 1 << 40, count: 50 }.encode()) else { panic!() };
+        assert_eq!((p.before, p.count), (1 << 40, 50));
+        let Ok(PktC2S::Whisper(p)) = c2s(PktC2S_Whisper{ target: SessionId(42), msg: "psst".into() }.encode()) else { panic!() };
+        assert_eq!((p.target, p.msg.as_str()), (SessionId(42), "psst"));
+        assert!(matches!(c2s(PktC2S_Goodbye{}.encode()), Ok(PktC2S::Goodbye(_))));
+    }
+
+    #[test]
+    fn s2c_round_trip(){
+        let packets: Vec<PktS2C> = vec![
+            PktS2C_HelloReply::new(SessionId(3), ResumeToken([1; 16]), PROTOCOL_VERSION, Capabilities(3), "Kiwi123".into()).into(),
+            PktS2C_ChatHistory::new(10, true, vec![msg("one"), msg(""), msg("three")]).into(),
+            PktS2C_LobbyInfo::new(vec![Participant::new(SessionId(1), "Lime".into(), true, Link::Unstable, Role::Host)]).into(),
+            PktS2C_SetNameReply::new(NameResult::Taken, "Lime".into()).into(),
+            PktS2C_Notice::new(NoticeKind::Command, "a\nb".into()).into(),
+            PktS2C_Whisper::new(SessionId(8), msg("hi")).into(),
+            PktS2C_RoomList::new(vec![]).into(),
+        ];
+        for packet in packets {
+            let before = format!("{:?}", packet);
+            let after = s2c(packet.encode()).map(|x| format!("{:?}", x));
+            assert_eq!(after, Ok(before));
         }
-        return enc.consume();
     }
-}
 
-// Universal decode function
-pub fn decode(src: Vec<u8>) -> R<PktC2S>{
-    let mut src = Decoder::new(src);
-    let kind = src.get_u8()?;
-    let Some(kind) = PktC2Sid::from_u8(kind) else { return Err(()) };
-    use PktC2Sid::*;
-    let result = match kind{
-        Hello   => PktC2S_Hello::decode(&mut src)?.into(),
-        SendMsg => PktC2S_SendMsg::decode(&mut src)?.into(),
-        SetName => PktC2S_SetName::decode(&mut src)?.into(),
-        Goodbye => PktC2S_Goodbye::decode(&mut src)?.into(),
-        Buttons => PktC2S_Buttons::decode(&mut src)?.into(),
-    };
-    return Ok(result);
+    #[test]
+    fn truncated_input_is_refused(){
+        assert!(c2s(vec![]).is_err());
+        assert!(c2s(vec![200]).is_err()); // No such packet
+        // Part of a resume token
+        let hello = PktC2S_Hello{ version: 5, caps: Capabilities(1), resume: Some(ResumeToken([2; 16])) }.encode();
+        assert!(c2s(hello[..hello.len() - 1].to_vec()).is_err());
+        let fetch = PktC2S_FetchHistory{ before: 1000, count: 20 }.encode();
+        assert!(c2s(fetch[..fetch.len() - 1].to_vec()).is_err());
+        let whisper = PktC2S_Whisper{ target: SessionId(42), msg: "x".into() }.encode();
+        assert!(c2s(whisper[..5].to_vec()).is_err());
+        // Every cut of a packet with required fields throughout fails rather than panics
+        let history = PktS2C_ChatHistory::new(0, false, vec![msg("one"), msg("two")]).encode();
+        for cut in 0..history.len() {
+            assert!(s2c(history[..cut].to_vec()).is_err(), "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn huge_array_length_is_refused(){
+        // ChatHistory claiming 2^28 - 1 messages, with none there
+        let bytes = vec![PktS2Cid::ChatHistory as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x7f];
+        assert!(s2c(bytes).is_err());
+    }
 }
@@ -0,0 +1,233 @@
+//! Renders the web client's packet module (`webclient/src/packets.ts`) from the packet definitions.
+//! The client only encodes C2S packets and decodes S2C packets.
+//! The primitives it calls into are hand-written in `webclient/src/codec.ts`.
+
+use std::fmt::Write;
+
+use super::{Capabilities, PacketSchema, PktC2S, PktS2C, WireType, PROTOCOL_VERSION};
+
+/// Location of the generated module, relative to the crate root
+pub const OUTPUT_PATH: &str = "webclient/src/packets.ts";
+
+pub fn render()->String{
+    let c2s = PktC2S::schema();
+    let s2c = PktS2C::schema();
+    let mut out = String::new();
+    let o = &mut out;
+
+    wl(o, "// GENERATED FILE: run `cargo run --bin gen-packets` after changing src/packets.rs");
+    wl(o, "import { PacketEncoder, PktDecoder } from \"./codec\"");
+    wl(o, "");
+    wl(o, &format!("export const PROTOCOL_VERSION = {PROTOCOL_VERSION};"));
+    wl(o, "export enum Capabilities{");
+    for (name, cap) in Capabilities::ALL{
+        wl(o, &format!("    {name} = {},", cap.0));
+    }
+    wl(o, "}");
+    let mut records = vec![];
+    let mut enums = vec![];
+    for p in c2s.iter().chain(s2c.iter()){
+        for (_, ty) in p.fields.iter(){ collect_named(ty, &mut records, &mut enums); }
+    }
+    for (name, variants) in enums.iter(){
+        wl(o, &format!("export enum {name}{{"));
+        for (variant, val) in variants.iter(){
+            wl(o, &format!("    {variant} = {val},"));
+        }
+        wl(o, "}");
+    }
+    wl(o, "");
+    wl(o, "// Records");
+    wl(o, "// ---------------");
+    for (name, fields) in records.iter(){
+        wl(o, "");
+        wl(o, &format!("export type {name} = {{"));
+        for (field, ty) in fields.iter(){
+            wl(o, &format!("    {field}: {},", ts_type(ty)));
+        }
+        wl(o, "}");
+        wl(o, &format!("export function encode_{name}(enc: PacketEncoder, x: {name}){{"));
+        for (field, ty) in fields.iter(){
+            wl(o, &format!("    {}", ts_encode(ty, &format!("x.{field}"))));
+        }
+        wl(o, "}");
+        wl(o, &format!("export function decode_{name}(d: PktDecoder): {name}{{"));
+        wl(o, "    return {");
+        for (field, ty) in fields.iter(){
+            wl(o, &format!("        {field}: {},", ts_decode(ty)));
+        }
+        wl(o, "    };");
+        wl(o, "}");
+    }
+    wl(o, "");
+    wl(o, "// Encoding");
+    wl(o, "// ---------------");
+    id_enum(o, "PktC2Sid", &c2s);
+    for p in c2s.iter(){
+        let args: Vec<String> = p.fields.iter().map(|(name, ty)| format!("{name}: {}", ts_type(ty))).collect();
+        wl(o, "");
+        wl(o, &format!("export function encode_C2S_{}({}){{", p.name, args.join(", ")));
+        wl(o, "    let enc = new PacketEncoder();");
+        wl(o, &format!("    enc.append_u8(PktC2Sid.{});", p.name));
+        for (name, ty) in p.fields.iter(){
+            wl(o, &format!("    {}", ts_encode(ty, name)));
+        }
+        wl(o, "    return enc.finish();");
+        wl(o, "}");
+    }
+
+    wl(o, "");
+    wl(o, "// Decoding");
+    wl(o, "// ---------------");
+    id_enum(o, "PktS2Cid", &s2c);
+    for p in s2c.iter(){
+        wl(o, "");
+        wl(o, &format!("export type PktS2C_{} = {{", p.name));
+        for (name, ty) in p.fields.iter(){
+            wl(o, &format!("    {name}: {},", ts_type(ty)));
+        }
+        wl(o, "}");
+    }
+    wl(o, "");
+    wl(o, "export type PacketS2C =");
+    for p in s2c.iter(){
+        wl(o, &format!("    | {{id: PktS2Cid.{0}}} & PktS2C_{0}", p.name));
+    }
+    wl(o, ";");
+    wl(o, "");
+    wl(o, DECODE_PACKET);
+    for p in s2c.iter(){
+        wl(o, "");
+        wl(o, &format!("let decode_S2C_{0}: DecoderFunction<PktS2C_{0}> = (d)=>{{", p.name));
+        wl(o, "    return {");
+        for (name, ty) in p.fields.iter(){
+            wl(o, &format!("        {name}: {},", ts_decode(ty)));
+        }
+        wl(o, "    };");
+        wl(o, "}");
+    }
+    wl(o, "");
+    wl(o, "// \"Lookup table\" that decodes incoming packets into legible types.");
+    wl(o, "const PktDecodeLookup: { [id in PktS2Cid]: DecoderFunction<any>} = {");
+    for p in s2c.iter(){
+        wl(o, &format!("    [PktS2Cid.{0}]: decode_S2C_{0},", p.name));
+    }
+    wl(o, "};");
+    return out;
+}
+
+// The fixed part of the decoder
+const DECODE_PACKET: &str = r#"export enum ParseError{
+    Unimplemented,
+    UnknownPacket
+}
+
+type DecoderResult<T> = T | ParseError;
+type DecoderFunction<T = void> = (decoder: PktDecoder) => DecoderResult<T>
+
+export function decode_packet(buffer: ArrayBuffer): PacketS2C | ParseError{
+    let decoder = new PktDecoder(buffer);
+    let id = decoder.get_u8();
+    let decode_function = PktDecodeLookup[id as PktS2Cid];
+    if(!decode_function){
+        return ParseError.UnknownPacket
+    }
+    let result = decode_function(decoder);
+    if(result in ParseError){
+        return result;
+    }
+    return {
+        id: id,
+        ...result
+    }
+}"#;
+
+/// Write line
+fn wl(out: &mut String, line: &str){
+    let _ = writeln!(out, "{line}");
+}
+
+fn id_enum(out: &mut String, name: &str, packets: &[PacketSchema]){
+    wl(out, &format!("export enum {name}{{"));
+    for p in packets{
+        wl(out, &format!("    {} = {},", p.name, p.id));
+    }
+    wl(out, "}");
+}
+
+type Named<T> = Vec<(&'static str, Vec<(&'static str, T)>)>;
+/// Every record and enum used by `ty`, in the order they're first seen
+fn collect_named(ty: &WireType, records: &mut Named<WireType>, enums: &mut Named<u8>){
+    use WireType::*;
+    match ty{
+        Arr(t) | Opt(t) => collect_named(t, records, enums),
+        Record(name, fields) => {
+            for (_, t) in fields.iter(){ collect_named(t, records, enums); }
+            if !records.iter().any(|x| x.0 == *name) { records.push((name, fields.clone())); }
+        }
+        Enum(name, variants) if !enums.iter().any(|x| x.0 == *name) => enums.push((name, variants.clone())),
+        _ => {}
+    }
+}
+
+fn ts_type(ty: &WireType)->String{
+    use WireType::*;
+    match ty{
+        U8 | UVarint | U64 => "number".into(),
+        Bool => "boolean".into(),
+        Str | ExhaustiveStr => "string".into(),
+        SessionId | Bytes(_) => "Uint8Array".into(),
+        Arr(t) => match **t {
+            Opt(_) => format!("({})[]", ts_type(t)),
+            _ => format!("{}[]", ts_type(t)),
+        },
+        Opt(t) => format!("{} | null", ts_type(t)),
+        Record(name, _) | Enum(name, _) => name.to_string(),
+    }
+}
+
+/// A statement that appends `val` to `enc`
+fn ts_encode(ty: &WireType, val: &str)->String{
+    use WireType::*;
+    match ty{
+        U8 | Enum(..) => format!("enc.append_u8({val});"),
+        Bool => format!("enc.append_u8(+{val});"),
+        UVarint => format!("enc.append_uvarint({val});"),
+        U64 => format!("enc.append_u64({val});"),
+        Str => format!("enc.append_str({val});"),
+        ExhaustiveStr => format!("enc.append_exhaustive_str({val});"),
+        SessionId => format!("enc.append_sessionid({val});"),
+        Bytes(_) => format!("enc.append_bytes({val});"),
+        Arr(t) => format!("enc.append_arr({val}, (enc, x)=>{{ {} }});", ts_encode(t, "x")),
+        Opt(t) => format!("if({val} !== null){{ {} }}", ts_encode(t, val)),
+        Record(name, _) => format!("encode_{name}(enc, {val});"),
+    }
+}
+
+/// An expression that reads a value from `d`
+fn ts_decode(ty: &WireType)->String{
+    use WireType::*;
+    match ty{
+        U8 | Enum(..) => "d.get_u8()".into(),
+        Bool => "d.get_u8() !== 0".into(),
+        UVarint => "d.get_uvarint()".into(),
+        U64 => "d.get_u64()".into(),
+        Str => "d.get_str()".into(),
+        ExhaustiveStr => "d.get_str_exhaustive()".into(),
+        SessionId => "d.get_sessionid()".into(),
+        Bytes(n) => format!("d.get_bytes({n})"),
+        Arr(t) => format!("d.get_arr((d)=>{})", ts_decode(t)),
+        Opt(t) => format!("d.remaining() > 0 ? {} : null", ts_decode(t)),
+        Record(name, _) => format!("decode_{name}(d)"),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    #[test]
+    fn checked_in_typescript_is_current(){
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(super::OUTPUT_PATH);
+        let current = std::fs::read_to_string(&path).unwrap_or_default().replace("\r\n", "\n");
+        assert!(current == super::render(), "{} is stale. Regenerate it with `cargo run --bin gen-packets`", path.display());
+    }
+}
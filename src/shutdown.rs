@@ -0,0 +1,75 @@
+//! Server-wide shutdown signal, raised by ctrl+c or the console's /shutdown.
+//! Sessions hold a `SessionGuard` so the process can wait for them to say goodbye before exiting.
+
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::chatroom::ROOMS;
+
+/// Told to clients when no other reason is given
+pub const DEFAULT_REASON: &str = "The server is shutting down.";
+
+lazy_static!{
+    // Why the server is shutting down, once it is
+    static ref SHUTDOWN: watch::Sender<Option<String>> = watch::channel(None).0;
+    // Number of sessions yet to finish
+    static ref SESSIONS: watch::Sender<usize> = watch::channel(0).0;
+}
+
+/// Asks everything to stop. Only the first reason given is kept.
+pub fn request(reason: impl Into<String>){
+    let reason = reason.into();
+    let first = SHUTDOWN.send_if_modified(|x| {
+        if x.is_some() { return false; }
+        *x = Some(reason.clone());
+        return true;
+    });
+    if first { info!("Shutting down ({})", reason); }
+}
+
+/// Completes once shutdown has been requested. Completes immediately if it already has been.
+pub async fn requested(){
+    let _ = SHUTDOWN.subscribe().wait_for(|x| x.is_some()).await;
+}
+
+/// Why the server is shutting down, if it is
+pub fn reason()->Option<String>{
+    return SHUTDOWN.borrow().clone();
+}
+
+/// Requests shutdown on ctrl+c
+pub async fn on_ctrl_c(){
+    if tokio::signal::ctrl_c().await.is_ok() { request(DEFAULT_REASON); }
+}
+
+/// Held by a session while it runs, so shutdown can wait for it
+pub struct SessionGuard(());
+
+/// Counts a session in until the guard is dropped
+pub fn hold()->SessionGuard{
+    SESSIONS.send_modify(|x| *x += 1);
+    return SessionGuard(());
+}
+impl Drop for SessionGuard{
+    fn drop(&mut self){
+        SESSIONS.send_modify(|x| *x -= 1);
+    }
+}
+
+/// Waits for every session to finish, giving up after `deadline`
+pub async fn sessions_finished(deadline: Duration){
+    let mut sessions = SESSIONS.subscribe();
+    if tokio::time::timeout(deadline, sessions.wait_for(|x| *x == 0)).await.is_err() {
+        warn!("Gave up waiting on {} sessions to close", *SESSIONS.borrow());
+    }
+}
+
+/// Waits for the chat logs to be written out, giving up after `deadline`
+pub async fn logs_written(deadline: Duration){
+    if tokio::time::timeout(deadline, ROOMS.flush_logs()).await.is_err() {
+        warn!("Gave up waiting on the chat logs to be written");
+    }
+}
@@ -0,0 +1,244 @@
+//! Full-screen dashboard for the host (`tui` feature). Stands in for the plain console when run in a terminal.
+
+use std::{collections::VecDeque, sync::{atomic::{AtomicBool, Ordering}, Mutex}, time::Duration};
+
+use lazy_static::lazy_static;
+use log::{Log, Metadata, Record};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table, TableState},
+    Frame,
+};
+
+use crate::{chatroom::ROOMS, config, console, fi, packets::{ChatMsg, MsgKind, Participant}, shutdown, webrtcsignalling};
+
+/// How often the dashboard refreshes
+const TICK: Duration = Duration::from_millis(100);
+/// Lines of log output kept for the log pane
+const LOG_LINES: usize = 500;
+/// Most recent messages shown from each room
+const CHAT_LINES: usize = 100;
+
+lazy_static!{
+    static ref LOGGER: PaneLogger = PaneLogger{ lines: Mutex::new(VecDeque::new()), closed: AtomicBool::new(false) };
+}
+
+/// Keeps log output for the log pane, rather than writing over the dashboard
+struct PaneLogger{
+    lines: Mutex<VecDeque<String>>,
+    // Set once the dashboard is gone, after which lines go to stderr
+    closed: AtomicBool,
+}
+impl PaneLogger{
+    fn push(&self, line: String){
+        let mut lines = self.lines.lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            drop(lines);
+            eprintln!("{}", line);
+            return;
+        }
+        if lines.len() == LOG_LINES { lines.pop_front(); }
+        lines.push_back(line);
+    }
+}
+impl Log for PaneLogger{
+    fn enabled(&self, metadata: &Metadata)->bool{
+        // Same filtering as the plain console
+        let config = config::get();
+        let max = fi!(metadata.target().starts_with("webrtc_ice"), config.ice_log_level, config.log_level);
+        return metadata.level() <= max;
+    }
+    fn log(&self, record: &Record){
+        if !self.enabled(record.metadata()) { return; }
+        self.push(format!("[{}] {}", record.level(), record.args()));
+    }
+    fn flush(&self){}
+}
+
+/// Sends log output to the dashboard's log pane
+pub fn init_logger(){
+    let _ = log::set_logger(&*LOGGER);
+    log::set_max_level(config::get().log_level.max(config::get().ice_log_level));
+}
+
+/// Runs the dashboard until shutdown
+pub async fn run(){
+    let mut terminal = ratatui::init();
+    let mut dash = Dashboard{ selected: TableState::default(), prompt: None, snapshot: Snapshot::gather().await };
+    loop{
+        dash.snapshot = Snapshot::gather().await;
+        let _ = terminal.draw(|frame| dash.draw(frame));
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            if let Ok(Event::Key(key)) = event::read() {
+                if key.kind == KeyEventKind::Press { dash.on_key(key).await; }
+            }
+        }
+        tokio::select!{
+            _ = tokio::time::sleep(TICK) => {},
+            _ = shutdown::requested() => break,
+        }
+    }
+    // Shutdown carries on after the dashboard is gone, so the log goes back to stderr.
+    // Holding the lines keeps anything logged meanwhile waiting until the terminal is back to normal.
+    let lines = LOGGER.lines.lock().unwrap();
+    LOGGER.closed.store(true, Ordering::Relaxed);
+    ratatui::restore();
+    drop(lines);
+}
+
+struct Member{
+    room: String,
+    p: Participant,
+    address: String,
+    muted: bool,
+}
+
+// Everything on screen, gathered each tick
+struct Snapshot{
+    members: Vec<Member>,
+    chat: Vec<(String, ChatMsg)>,
+    offers: Vec<(String, Duration)>,
+}
+impl Snapshot{
+    async fn gather()->Self{
+        let mut members = vec![];
+        let mut chat = vec![];
+        for room in ROOMS.all().await {
+            for (p, user) in room.members().await {
+                let (address, muted) = { let u = user.read().await; (u.address.clone(), u.muted()) };
+                members.push(Member{ room: room.name.clone(), p, address, muted });
+            }
+            let recent = room.history_page(usize::MAX, CHAT_LINES).await.msgs;
+            chat.extend(recent.into_iter().map(|x| (room.name.clone(), x)));
+        }
+        chat.sort_by_key(|x| x.1.id);
+        return Self{ members, chat, offers: webrtcsignalling::pending_offers() };
+    }
+}
+
+enum Prompt{
+    Announce,
+    Command,
+    /// Asks before quitting, as it's one key away from the others
+    Quit,
+}
+
+struct Dashboard{
+    selected: TableState,
+    // What's being typed at the bottom of the screen
+    prompt: Option<(Prompt, String)>,
+    snapshot: Snapshot,
+}
+impl Dashboard{
+    async fn on_key(&mut self, key: KeyEvent){
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            shutdown::request(shutdown::DEFAULT_REASON);
+            return;
+        }
+        if let Some((Prompt::Quit, _)) = self.prompt {
+            self.prompt = None;
+            if matches!(key.code, KeyCode::Char('y') | KeyCode::Char('q')) { shutdown::request(shutdown::DEFAULT_REASON); }
+            return;
+        }
+        if let Some((kind, text)) = &mut self.prompt {
+            match key.code{
+                KeyCode::Char(c) => text.push(c),
+                KeyCode::Backspace => { text.pop(); },
+                KeyCode::Esc => self.prompt = None,
+                KeyCode::Enter => {
+                    let command = match kind{
+                        Prompt::Announce => format!("/say {}", text),
+                        Prompt::Command => text.clone(),
+                        Prompt::Quit => return,
+                    };
+                    self.prompt = None;
+                    self.run(&command).await;
+                }
+                _ => {}
+            }
+            return;
+        }
+        let count = self.snapshot.members.len();
+        let selected = self.selected.selected().and_then(|x| self.snapshot.members.get(x));
+        match key.code{
+            KeyCode::Up => self.selected.select(Some(self.selected.selected().map_or(0, |x| x.saturating_sub(1)))),
+            KeyCode::Down if count > 0 => self.selected.select(Some(self.selected.selected().map_or(0, |x| (x + 1).min(count - 1)))),
+            KeyCode::Char('k') => if let Some(m) = selected {
+                let command = format!("/kick {}", m.p.sid);
+                self.run(&command).await;
+            },
+            KeyCode::Char('m') => if let Some(m) = selected {
+                let command = format!("/{} {}", fi!(m.muted, "unmute", "mute"), m.p.sid);
+                self.run(&command).await;
+            },
+            KeyCode::Char('a') => self.prompt = Some((Prompt::Announce, String::new())),
+            KeyCode::Char(':') | KeyCode::Char('/') => self.prompt = Some((Prompt::Command, String::new())),
+            KeyCode::Char('q') => self.prompt = Some((Prompt::Quit, String::new())),
+            _ => {}
+        }
+    }
+
+    // Runs a console command, putting what it says in the log pane
+    async fn run(&self, command: &str){
+        LOGGER.push(format!("> {}", command));
+        for line in console::run_command(command).await.lines() {
+            LOGGER.push(line.to_owned());
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame){
+        let [top, bottom, status] = Layout::vertical([Constraint::Percentage(50), Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [people, chat] = Layout::horizontal([Constraint::Percentage(60), Constraint::Fill(1)]).areas(top);
+        let [logs, offers] = Layout::horizontal([Constraint::Percentage(70), Constraint::Fill(1)]).areas(bottom);
+
+        // Participants
+        let members = &self.snapshot.members;
+        if let Some(x) = self.selected.selected() {
+            self.selected.select(fi!(members.is_empty(), None, Some(x.min(members.len().saturating_sub(1)))));
+        }
+        let rows = members.iter().map(|m| Row::new(vec![
+            m.room.clone(), m.p.sid.to_string(), m.p.name.clone(), format!("{:?}", m.p.role), format!("{:?}", m.p.link),
+            fi!(m.p.hand, "👋", "").to_owned(), fi!(m.muted, "muted", "").to_owned(), m.address.clone(),
+        ]));
+        let widths = [
+            Constraint::Length(12), Constraint::Length(16), Constraint::Length(20), Constraint::Length(10),
+            Constraint::Length(9), Constraint::Length(2), Constraint::Length(5), Constraint::Fill(1),
+        ];
+        let table = Table::new(rows, widths)
+            .header(Row::new(vec!["Room", "Id", "Name", "Role", "Link", "", "", "Address"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::bordered().title(format!(" Participants ({}) ", members.len())));
+        frame.render_stateful_widget(table, people, &mut self.selected);
+
+        // Chat, logs and offers only show what fits, newest at the bottom
+        let lines = self.snapshot.chat.iter().map(|(room, msg)| match msg.kind{
+            MsgKind::User => format!("[{}] {}) {}", room, msg.name, msg.text),
+            MsgKind::System => format!("[{}] >>> {}", room, msg.text),
+            MsgKind::Action => format!("[{}] * {} {}", room, msg.name, msg.text),
+        });
+        tail_pane(frame, chat, " Chat ", lines);
+        let logged: Vec<String> = LOGGER.lines.lock().unwrap().iter().cloned().collect();
+        tail_pane(frame, logs, " Log ", logged.into_iter());
+        let pending = self.snapshot.offers.iter().map(|(source, age)| format!("{} ({}s)", source, age.as_secs()));
+        tail_pane(frame, offers, &format!(" Pending offers ({}) ", self.snapshot.offers.len()), pending);
+
+        let status_line = match &self.prompt{
+            Some((Prompt::Announce, text)) => format!("Announce: {}_", text),
+            Some((Prompt::Command, text)) => format!("Command: /{}_", text.trim_start_matches('/')),
+            Some((Prompt::Quit, _)) => "Shut the server down? y/q to confirm, any other key to cancel".to_owned(),
+            None => "↑↓ select  k kick  m mute/unmute  a announce  : command  q quit".to_owned(),
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+}
+
+// A bordered pane showing the last lines that fit
+fn tail_pane(frame: &mut Frame, area: Rect, title: &str, lines: impl DoubleEndedIterator<Item = String>){
+    let height = area.height.saturating_sub(2) as usize;
+    let mut shown: Vec<Line> = lines.rev().take(height).map(Line::from).collect();
+    shown.reverse();
+    frame.render_widget(Paragraph::new(shown).block(Block::bordered().title(title.to_owned())), area);
+}
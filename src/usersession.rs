@@ -1,88 +1,286 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::{Duration, Instant}};
 
 use bytes::Bytes;
 use derive_more::derive::Display;
-use just_webrtc::types::PeerConnectionState;
 use lazy_static::lazy_static;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 use log::{info, warn};
-use tokio::sync::{RwLock, RwLockReadGuard};
-use just_webrtc::platform::Error as WebRTCError;
+use tokio::sync::{mpsc, oneshot, RwLock, RwLockReadGuard};
+use webrtc::{peer_connection::peer_connection_state::RTCPeerConnectionState, Error as WebRTCError};
 
 use crate::{
-    chatroom::{ChatMsg, LobbyHandle, ParticipantMsg, LOBBY},
-    packets::{self, Encode, PktS2C_ReceiveMsg, PktS2C_SetNameReply},
-    util::UUIDGen, webrtcpeer::{ClientConnection, RecvError}
+    commands, config, fi, chatfilter::{self, ChatLimiter}, chatroom::{self, chat_msg, system_msg, Lobby, LobbyHandle, ParticipantMsg, HISTORY_PAGE_MAX, ROOMS},
+    packets::{self, Capabilities, Encode, Link, MsgKind, NoticeKind, PktS2C_Kicked, PktS2C_Notice, PktS2C_ReceiveMsg, PktS2C_Whisper, PktS2C_RoomList, PktS2C_ServerClosing, NameResult, PktS2C_SetNameReply, Role},
+    names, shutdown, webrtcpeer::{ChannelSpec, ClientConnection, Incoming, RecvError}
 };
 
+/// How long a session outlives its connection, waiting for the client to resume it
+const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(120);
+/// How long resuming waits for a still connected session to hand itself over
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wrong host passphrases a session, or an address, can give before it has to wait
+const CLAIM_ATTEMPTS: u32 = 3;
+/// How long after the last wrong host passphrase until another can be tried, once out of attempts
+const CLAIM_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// A UserSession shared between its connection and the lobby
+pub type SharedUser = Arc<RwLock<UserSession>>;
+
+lazy_static!{
+    // Sessions that lost their connection and may still be resumed with their ResumeToken
+    static ref DETACHED_SESSIONS: Mutex<HashMap<ResumeToken, DetachedSession>> = Mutex::new(HashMap::new());
+    // Sessions with a connection, which a client holding the ResumeToken can take over.
+    // Locked before DETACHED_SESSIONS whenever both are, so a session is always in one or the other while it moves.
+    static ref ATTACHED_SESSIONS: Mutex<HashMap<ResumeToken, mpsc::Sender<Takeover>>> = Mutex::new(HashMap::new());
+    // Wrong host passphrases by address, so reconnecting doesn't give more tries
+    static ref CLAIMS_BY_IP: Mutex<HashMap<IpAddr, ClaimAttempts>> = Mutex::new(HashMap::new());
+}
+
+// Asks a connected session to hand itself over to a new connection. It answers once its old connection is closed.
+type Takeover = oneshot::Sender<(SharedUser, Arc<Lobby>)>;
+
+/// Something only some roles may do
+#[derive(Clone, Copy, Debug)]
+pub enum Permission{
+    Chat,
+    Rename,
+    Buttons,
+    /// Joining and creating rooms, and fetching older chat
+    ManageRooms,
+    /// Not having to wait between messages in slow mode
+    SkipSlowMode,
+}
+impl Permission{
+    /// The lowest role allowed to
+    pub fn min_role(self)->Role{
+        use Permission::*;
+        match self{
+            Chat | Rename | Buttons | ManageRooms => Role::Member,
+            SkipSlowMode => Role::Moderator,
+        }
+    }
+}
+
+struct DetachedSession{
+    sid: SessionId,
+    user: SharedUser,
+    // The room it is still a member of
+    room: Arc<Lobby>,
+    since: Instant,
+}
+
+/// Reclaims a session and the room it was in, if it has not expired.
+/// A session that still has a connection is taken from it, as the client often comes back before the server notices it left.
+pub async fn resume_session(token: ResumeToken)->Option<(SharedUser, Arc<Lobby>)>{
+    let (reply_tx, reply_rx) = oneshot::channel();
+    {
+        let mut attached = ATTACHED_SESSIONS.lock().unwrap();
+        let asked = attached.remove(&token).is_some_and(|x| x.try_send(reply_tx).is_ok());
+        if !asked {
+            let detached = DETACHED_SESSIONS.lock().unwrap().remove(&token)?;
+            info!("Resuming session {}", detached.sid);
+            return Some((detached.user, detached.room));
+        }
+    }
+    // Dropped if the session ended for good in the meantime
+    let (user, room) = tokio::time::timeout(TAKEOVER_TIMEOUT, reply_rx).await.ok()?.ok()?;
+    info!("Resuming session {} from another connection", user.read().await.id);
+    return Some((user, room));
+}
+
+// Lets a client holding the token take the session over while it has a connection
+fn attach_session(token: ResumeToken)->mpsc::Receiver<Takeover>{
+    let (tx, rx) = mpsc::channel(1);
+    ATTACHED_SESSIONS.lock().unwrap().insert(token, tx);
+    return rx;
+}
+
+// The session's connection has ended. Gives a takeover that came in before it could stop them, else parks the session if it's given.
+fn end_attachment(token: ResumeToken, takeover: &mut mpsc::Receiver<Takeover>, park: Option<(SessionId, SharedUser, Arc<Lobby>)>)->Option<Takeover>{
+    let mut attached = ATTACHED_SESSIONS.lock().unwrap();
+    attached.remove(&token);
+    takeover.close();
+    if let Ok(reply) = takeover.try_recv() { return Some(reply); }
+    if let Some((sid, user, room)) = park { park_session(sid, token, user, room); }
+    return None;
+}
+
+/// Drops a detached session, so it can't be resumed
+pub fn forget_session(sid: SessionId){
+    DETACHED_SESSIONS.lock().unwrap().retain(|_, x| x.sid != sid);
+}
+
+/// Keeps the session around for the grace period, then has it leave its room for good.
+fn park_session(sid: SessionId, token: ResumeToken, user: SharedUser, room: Arc<Lobby>){
+    DETACHED_SESSIONS.lock().unwrap().insert(token, DetachedSession{ sid, user, room: room.clone(), since: Instant::now() });
+    tokio::spawn(async move{
+        tokio::time::sleep(SESSION_GRACE_PERIOD).await;
+        let expired = {
+            let mut detached = DETACHED_SESSIONS.lock().unwrap();
+            // Only if it wasn't resumed (and perhaps detached again) in the meantime
+            let expired = detached.get(&token).is_some_and(|x| x.since.elapsed() >= SESSION_GRACE_PERIOD);
+            if expired { detached.remove(&token); }
+            expired
+        };
+        if expired {
+            info!("Session {} expired", sid);
+            room.remove(sid).await;
+        }
+    });
+}
+
+// How a session's connection came to an end
+enum Ending{
+    /// The client may come back to resume the session
+    LinkLost,
+    /// Goodbye, kicked, or misbehaving
+    Over,
+    Shutdown,
+    /// The client resumed the session on a new connection
+    TakenOver(Takeover),
+}
+
 // #[derive(Deref)]
 pub struct ActiveSession{
     conn: ClientConnection,
-    pub user: Arc<RwLock<UserSession>>
+    pub user: Arc<RwLock<UserSession>>,
+    /// Optional features both sides agreed on in Hello
+    caps: Capabilities,
 }
 impl ActiveSession{
-    pub fn new(conn: ClientConnection)->Self{
-        Self{conn, user: Arc::new(RwLock::new(UserSession::new()))}
+    /// `user` is either a new UserSession, or one reclaimed with `resume_session`.
+    pub fn new(conn: ClientConnection, caps: Capabilities, user: Arc<RwLock<UserSession>>)->Self{
+        Self{conn, user, caps}
     }
-    pub async fn recv(&self)->Result<Bytes, RecvError>{
+    pub async fn recv(&self)->Result<Incoming, RecvError>{
         self.conn.recv().await
     }
     // usize = bytes sent
     pub async fn send(&self, data: impl Into<Bytes>)->Result<usize, WebRTCError>{
         self.conn.send(data).await
     }
+    /// Sends a packet over RELIABLE or UNRELIABLE. usize = bytes sent
+    pub async fn send_packet(&self, spec: &ChannelSpec, pkt: impl Encode)->Result<usize, WebRTCError>{
+        self.conn.send_packet(spec, pkt).await
+    }
+    /// Sends over a registered channel, for its handler. usize = bytes sent
+    pub async fn send_on(&self, spec: &ChannelSpec, data: impl Into<Bytes>)->Result<usize, WebRTCError>{
+        self.conn.send_on(spec, data).await
+    }
     pub async fn user(&self)->RwLockReadGuard<'_, UserSession>{
         self.user.read().await
     }
+    pub fn caps(&self)->Capabilities{
+        self.caps
+    }
 
-    pub async fn handle_active_session(mut self){
-        let mut handle = LOBBY.join(&self).await;
-        loop{tokio::select! {
+    /// Runs the session in `room` until the connection ends, then closes the connection.
+    pub async fn handle_active_session(mut self, room: Arc<Lobby>){
+        let token = self.user().await.token;
+        let mut takeover = attach_session(token);
+        let mut handle = room.join(&self).await;
+        if self.caps.contains(Capabilities::ROOMS) {
+            let _ = self.send(handle.joined_packet()).await;
+        }
+        let ending = loop{tokio::select! {
             // Receive data. If error, drop the session.
             c2s = self.recv() => match c2s{
-                Ok(msg)=>{
-                    match self.handle_incoming(msg, &handle).await{
+                Ok(Incoming::Packet(_, pkt))=>{
+                    match self.handle_incoming(pkt, &mut handle).await{
                         Ok(_) => {},
-                        Err(_) => break,
+                        Err(_) => break Ending::Over,
                     };
                 },
-                // Connection shutdown
-                Err(RecvError::Abort)=>{ break; }
-                Err(_)=>{ warn!("Unexpected error. Closing the connection."); break; }
+                Ok(Incoming::Malformed(_, msg))=>{
+                    warn!("(DROPPING) {} >> {:?}", self.user().await.username, msg);
+                    break Ending::Over;
+                },
+                // Other channels are up to whoever registered them
+                Ok(Incoming::Data(spec, handler, data))=>{
+                    if handler(&self, data).await.is_err() {
+                        info!("Closing data channel {:?} for {}", spec.label, self.user().await.username);
+                        self.conn.close_channel(&spec).await;
+                    }
+                },
+                Err(RecvError::Abort)=>{ break Ending::Shutdown; }
+                Err(RecvError::Closed)=>{ info!("Channel closed."); break Ending::LinkLost; }
             },
             // Send data. If error, drop the session.
             s2c = handle.broadcast_rx.recv() => match s2c{
                 Ok(msg)=>{
-                    match self.handle_outgoing(msg).await{
-                        Ok(_) => {},
-                        Err(x) => { warn!("Error: Could not send to client {}", x); break; }
-                    }
+                    if let Err(ending) = self.handle_participant_msg(msg).await { break ending; }
                 },
-                Err(_)=>{ info!("Internal server error."); break; }
+                Err(_)=>{ info!("Internal server error."); break Ending::Over; }
             },
-            // FIXME: Use functions
             s2c = handle.individual_rx.recv() => match s2c{
                 Some(msg)=>{
-                    match self.handle_outgoing(msg).await{
-                        Ok(_) => {},
-                        Err(x) => { warn!("Error: Could not send to client {}", x); break; }
-                    }
+                    if let Err(ending) = self.handle_participant_msg(msg).await { break ending; }
                 },
-                None=>{ info!("Internal server error."); break; }
+                None=>{ info!("Internal server error."); break Ending::Over; }
             },
             // If the WebRTC state is failed, close the session.
-            state = self.conn.state_change() => match self.handle_connection_state_change(state).await{
+            state = self.conn.state_change() => match self.handle_connection_state_change(state, &handle).await{
                 Ok(_) => {},
-                Err(_) => { break; }
+                Err(_) => { break Ending::LinkLost; }
             },
-        }}
-        info!("Connection with {} has finished.", self.user().await.username);
+            Some(reply) = takeover.recv() => { break Ending::TakenOver(reply); },
+        }};
+        let (sid, username) = { let u = self.user().await; (u.id, u.username.clone()) };
+        let room = handle.room().clone();
+        let ending = match ending{
+            Ending::LinkLost => {
+                // Still a member while it waits
+                handle.detach().await;
+                let park = Some((sid, self.user.clone(), room.clone()));
+                end_attachment(token, &mut takeover, park).map_or(Ending::LinkLost, Ending::TakenOver)
+            }
+            Ending::TakenOver(reply) => {
+                handle.detach().await;
+                end_attachment(token, &mut takeover, None);
+                Ending::TakenOver(reply)
+            }
+            // Over for good, so a late takeover finds nothing
+            Ending::Over => {
+                end_attachment(token, &mut takeover, None);
+                Ending::Over
+            }
+            Ending::Shutdown => {
+                end_attachment(token, &mut takeover, None);
+                // Everyone is leaving at once, so nobody is told about it
+                let reason = shutdown::reason().unwrap_or_default();
+                let _ = self.send(PktS2C_ServerClosing::new(reason).encode()).await;
+                handle.detach().await;
+                Ending::Shutdown
+            }
+        };
+        match ending{
+            Ending::LinkLost => info!("Lost connection with {}. Holding the session for {:?}.", username, SESSION_GRACE_PERIOD),
+            Ending::Over => info!("Connection with {} has finished.", username),
+            Ending::Shutdown => info!("Closed connection with {}.", username),
+            Ending::TakenOver(reply) => {
+                info!("{} resumed their session on a new connection", username);
+                let _ = self.send(PktS2C_Kicked::new("You resumed this session somewhere else.".into()).encode()).await;
+                self.conn.close().await;
+                // Only once the old connection is gone, so nothing goes out twice
+                let _ = reply.send((self.user.clone(), room));
+                return;
+            }
+        }
+        self.conn.close().await;
     }
-    async fn handle_connection_state_change(&self, state: PeerConnectionState) -> Result<(),()>{
-        use PeerConnectionState::*;
+    async fn handle_connection_state_change(&self, state: RTCPeerConnectionState, handle: &LobbyHandle) -> Result<(),()>{
+        use RTCPeerConnectionState::*;
+        let sid = self.user().await.id;
         match state{
             Failed | Closed => return Err(()),
-            Connecting => info!("{} connecting...", self.user().await.id),
-            Disconnected => info!("Connection interrupted with {}", self.user().await.id),
+            Connecting => info!("{} connecting...", sid),
+            Connected => handle.room().set_link(sid, Link::Good).await,
+            Disconnected => {
+                info!("Connection interrupted with {}", sid);
+                handle.room().set_link(sid, Link::Unstable).await;
+            }
             _ => {}
         }
         return Ok(())
@@ -90,40 +288,83 @@ impl ActiveSession{
 
     // Handles incoming raw client messages and dispatches them to the appropriate location.
     // If Err(), the caller should drop the connection.
-    async fn handle_incoming(&mut self, msg: Bytes, handle: &LobbyHandle)->Result<(),()>{
+    async fn handle_incoming(&mut self, pkt: packets::PktC2S, handle: &mut LobbyHandle)->Result<(),()>{
         use packets::PktC2S::*;
-        use crate::chatroom::ChatMsg::*;
-
-        let Ok(pkt) = packets::decode(msg.to_vec()) else {
-            warn!("(DROPPING) {} >> {:?}", self.user().await.username, msg);
-            return Err(());
-        };
 
         'a:{
-            match pkt{
-                Buttons(p) => {
-                    self.user.write().await.raised_hand = p.pressed;
-                    LOBBY.update_lobby_participants().await; // NOT EFFICIENT, but present for the demo
-                    break 'a;
-                }
-                _ => {}
+            if let Buttons(p) = pkt {
+                if !self.caps.contains(Capabilities::BUTTONS) { break 'a; }
+                if !self.user().await.can(Permission::Buttons) { break 'a; }
+                // Sent 10x per second, so only pass on changes
+                let (sid, changed) = {
+                    let mut user = self.user.write().await;
+                    let changed = user.raised_hand != p.pressed;
+                    user.raised_hand = p.pressed;
+                    (user.id, changed)
+                };
+                if changed { handle.room().update_participant(sid).await; }
+                break 'a;
             }
             info!("{} >> {:?}", self.user().await.username, pkt);
             match pkt{
                 SendMsg(p)=>{
-                    let msg = format!("{}) {}", self.user().await.username, p.msg);
-                    LOBBY.send_message(ChatMsg::User(msg)).await;
+                    let text = chatfilter::clean(&p.msg);
+                    // A leading '/' is a command. '//' sends the message with one slash.
+                    match text.strip_prefix('/') {
+                        Some(line) if !line.starts_with('/') => commands::run(self, handle.room(), line).await,
+                        escaped => self.say(handle.room(), MsgKind::User, escaped.unwrap_or(&text)).await,
+                    }
+                }
+                Whisper(p)=>{
+                    self.whisper(handle.room(), p.target, &p.msg).await;
                 }
                 SetName(p)=>{
-                    // Send approval
-                    let _ = self.send(PktS2C_SetNameReply::new(p.name.clone()).encode()).await;
-                    // Propagate server message
-                    let announcement = format!(">>> {} is now {}", self.user().await.username, p.name);
-                    if self.user().await.username != p.name {
-                        let _ = LOBBY.broadcast_tx.send(ParticipantMsg::Message(Server(announcement)));
-                        self.user.write().await.username = p.name;
+                    self.set_name(handle.room(), &p.name).await;
+                }
+                FetchHistory(p) if self.caps.contains(Capabilities::HISTORY) =>{
+                    if !self.allowed(Permission::ManageRooms, "fetch older chat").await { return Ok(()); }
+                    let page = handle.room().history_page(usize::try_from(p.before).unwrap_or(usize::MAX), (p.count as usize).min(HISTORY_PAGE_MAX)).await;
+                    let _ = self.send(page.encode()).await;
+                }
+                ListRooms(_) if self.caps.contains(Capabilities::ROOMS) =>{
+                    let _ = self.send(PktS2C_RoomList::new(ROOMS.list().await).encode()).await;
+                }
+                JoinRoom(p) if self.caps.contains(Capabilities::ROOMS) =>{
+                    if !self.allowed(Permission::ManageRooms, "change rooms").await { return Ok(()); }
+                    let room = match chatroom::room_name(&p.name) {
+                        Some(name) => ROOMS.get(&name).await,
+                        None => None,
+                    };
+                    match room {
+                        Some(room) => self.switch_room(handle, room).await,
+                        None => self.notify(format!("There is no room called {}.", p.name)).await,
                     }
                 }
+                CreateRoom(p) if self.caps.contains(Capabilities::ROOMS) =>{
+                    if !self.allowed(Permission::ManageRooms, "create rooms").await { return Ok(()); }
+                    let Some(name) = chatroom::room_name(&p.name) else {
+                        self.notify("Room names can only have letters, digits, '-' and '_'.".into()).await;
+                        return Ok(());
+                    };
+                    let limited = {
+                        let mut u = self.user.write().await;
+                        let slow_exempt = u.can(Permission::SkipSlowMode);
+                        u.chat.check(None, slow_exempt)
+                    };
+                    if let Err((kind, text)) = limited {
+                        self.notice(kind, text).await;
+                        return Ok(());
+                    }
+                    let Some(room) = ROOMS.get_or_create(name).await else {
+                        self.notice(NoticeKind::NotAllowed, format!("There are already {} rooms. Join one of them instead.", config::get().max_rooms)).await;
+                        return Ok(());
+                    };
+                    self.switch_room(handle, room).await;
+                    // Everyone else's list is out of date, but they ask for it when they want it
+                }
+                ClaimHost(p)=>{
+                    self.claim_host(handle.room(), &p.passphrase).await;
+                }
                 Goodbye(_)=>{
                     return Err(());
                 }
@@ -136,44 +377,279 @@ impl ActiveSession{
         return Ok(());
     }
 
+    // Moves the session into `room`, leaving the one it was in
+    async fn switch_room(&self, handle: &mut LobbyHandle, room: Arc<Lobby>){
+        if Arc::ptr_eq(handle.room(), &room) { return; }
+        // Leave properly before coming back, else the rejoin could be mistaken for resuming
+        let old = std::mem::replace(handle, room.join(self).await);
+        old.leave().await;
+        let _ = self.send(handle.joined_packet()).await;
+    }
+    /// Checks the session may send a message now, counting it if so and telling the client if not. Gives the session's id and name.
+    /// `text` is the message, already cleaned. None for things that count as a message without text of their own, like dice rolls.
+    pub async fn may_chat(&self, text: Option<&str>)->Option<(SessionId, String)>{
+        let checked = {
+            let mut u = self.user.write().await;
+            if !u.can(Permission::Chat) {
+                Err((NoticeKind::NotAllowed, "Spectators can't chat.".to_owned()))
+            } else if u.muted() {
+                Err((NoticeKind::Muted, "You are muted.".to_owned()))
+            } else {
+                let slow_exempt = u.can(Permission::SkipSlowMode);
+                u.chat.check(text, slow_exempt).map(|_| (u.id, u.username.clone()))
+            }
+        };
+        match checked {
+            Ok(x) => return Some(x),
+            Err((kind, text)) => {
+                info!("Refused a message from {} ({:?})", self.user().await.username, kind);
+                self.notice(kind, text).await;
+                return None;
+            }
+        }
+    }
+    /// Posts a message or action to the room, if the session may
+    pub async fn say(&self, room: &Lobby, kind: MsgKind, msg: &str){
+        let text = chatfilter::clean(msg);
+        if text.is_empty() { return; }
+        let Some((sid, name)) = self.may_chat(Some(&text)).await else { return; };
+        room.send_message(chat_msg(kind, sid, name, text)).await;
+    }
+    /// Sends a direct message to someone in the room, if the session may
+    pub async fn whisper(&self, room: &Lobby, target: SessionId, msg: &str){
+        if target == self.user().await.id {
+            self.notice(NoticeKind::NoRecipient, "You can't whisper to yourself.".into()).await;
+            return;
+        }
+        let text = chatfilter::clean(msg);
+        if text.is_empty() { return; }
+        let Some((sid, name)) = self.may_chat(Some(&text)).await else { return; };
+        // Only ever between the two of them, so it stays out of the room's log
+        let packet = PktS2C_Whisper::new(target, chat_msg(MsgKind::User, sid, name, text)).encode();
+        if room.whisper(target, packet.clone()).await {
+            let _ = self.send(packet).await;
+        } else {
+            self.notice(NoticeKind::NoRecipient, "They aren't here any more.".into()).await;
+        }
+    }
+    /// Renames the session, if the session may and the name passes `names::check`. The client hears back in SetNameReply.
+    /// Each try counts against the chat limits, as everyone sees the rename.
+    pub async fn set_name(&self, room: &Lobby, new: &str){
+        let (sid, name, muted, allowed, limited) = {
+            let mut u = self.user.write().await;
+            let (muted, allowed, slow_exempt) = (u.muted(), u.can(Permission::Rename), u.can(Permission::SkipSlowMode));
+            let limited = allowed && !muted && u.chat.check(None, slow_exempt).is_err();
+            (u.id, u.username.clone(), muted, allowed, limited)
+        };
+        let checked = if !allowed {
+            Err(NameResult::NotAllowed)
+        } else if muted {
+            Err(NameResult::Muted)
+        } else if limited {
+            Err(NameResult::RateLimited)
+        } else {
+            names::check(new, sid).await
+        };
+        match checked {
+            Ok(new) => room.rename(sid, new).await,
+            Err(result) => {
+                info!("{} can't be renamed to {:?} ({:?})", name, new, result);
+                let _ = self.send(PktS2C_SetNameReply::new(result, name).encode()).await;
+            }
+        }
+    }
+    // Makes the session host if it gave the passphrase. Only a few wrong ones are allowed before it has to wait.
+    async fn claim_host(&self, room: &Lobby, given: &str){
+        let Some(passphrase) = config::get().host_passphrase.as_deref() else {
+            self.notify("This server has no host passphrase.".into()).await;
+            return;
+        };
+        let now = Instant::now();
+        let mut user = self.user.write().await;
+        let (sid, name, address, ip) = (user.id, user.username.clone(), user.address.clone(), user.ip());
+        let wait = {
+            let mut by_ip = CLAIMS_BY_IP.lock().unwrap();
+            let ip_wait = ip.and_then(|x| by_ip.get_mut(&x)).and_then(|x| x.wait(now));
+            user.claims.wait(now).max(ip_wait)
+        };
+        if let Some(wait) = wait {
+            drop(user);
+            warn!("{} ({}) tried to claim host while locked out", name, address);
+            self.notify(format!("Too many wrong passphrases. Try again in {} minutes.", wait.as_secs().div_ceil(60))).await;
+            return;
+        }
+        if bool::from(passphrase.as_bytes().ct_eq(given.as_bytes())) {
+            user.claims = ClaimAttempts::default();
+            drop(user);
+            info!("{} ({}) gave the host passphrase", name, address);
+            room.set_role(sid, Role::Host).await;
+            return;
+        }
+        user.claims.fail(now);
+        let failed = user.claims.failed;
+        if let Some(ip) = ip {
+            let mut by_ip = CLAIMS_BY_IP.lock().unwrap();
+            by_ip.retain(|_, x| now - x.last < CLAIM_COOLDOWN);
+            by_ip.entry(ip).or_default().fail(now);
+        }
+        drop(user);
+        warn!("{} ({}) gave the wrong host passphrase ({} of {} attempts)", name, address, failed, CLAIM_ATTEMPTS);
+        self.notify("That isn't the host passphrase.".into()).await;
+    }
+    /// Tells this client only
+    pub async fn notice(&self, kind: NoticeKind, text: String){
+        let _ = self.send(PktS2C_Notice::new(kind, text).encode()).await;
+    }
+    // Whether the session has the permission, telling the client if not. `what` finishes "You need to be a member to ..."
+    async fn allowed(&self, permission: Permission, what: &str)->bool{
+        if self.user().await.can(permission) { return true; }
+        info!("Refused {:?} for {}", permission, self.user().await.username);
+        let role = format!("{:?}", permission.min_role()).to_lowercase();
+        self.notice(NoticeKind::NotAllowed, format!("You need to be a {} to {}.", role, what)).await;
+        return false;
+    }
+    // Sends a server message to this client only
+    async fn notify(&self, text: String){
+        let _ = self.send(PktS2C_ReceiveMsg::new(system_msg(text)).encode()).await;
+    }
+
+    // Deals with something the lobby sent this session.
+    // Err() ends the session.
+    async fn handle_participant_msg(&self, msg: ParticipantMsg)->Result<(), Ending>{
+        if let ParticipantMsg::Disconnect(reason) = msg {
+            info!("Disconnecting {} ({})", self.user().await.username, reason);
+            let _ = self.send(PktS2C_Kicked::new(reason).encode()).await;
+            return Err(Ending::Over);
+        }
+        if let Err(x) = self.handle_outgoing(msg).await {
+            warn!("Error: Could not send to client {}", x);
+            return Err(Ending::LinkLost);
+        }
+        return Ok(());
+    }
+
     // Propagates outgoing messages onto the wire.
     // TODO: Should this be serialising messages or not?
-    async fn handle_outgoing(&self, msg: ParticipantMsg)->Result<usize, just_webrtc::platform::Error>{
-        use crate::chatroom::ChatMsg::*;
+    async fn handle_outgoing(&self, msg: ParticipantMsg)->Result<usize, WebRTCError>{
         let msg = match msg{
-            ParticipantMsg::Message(msg) => PktS2C_ReceiveMsg::new(match msg{User(x)=>x, Server(x)=>x}).encode(),
+            ParticipantMsg::Message(msg) => PktS2C_ReceiveMsg::new(msg).encode(),
             ParticipantMsg::RawPacket(x) => x,
+            ParticipantMsg::Disconnect(_) => return Ok(0), // Handled by handle_participant_msg
         };
         // info!("{} << {:?}", self.user.username, bytes);
         self.send(msg).await
     }
 }
 
-#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Public identifier of a session. Safe to show to anyone.
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[display("{_0:x}")]
 pub struct SessionId(pub u64);
+impl SessionId{
+    /// Stands in for the author of server messages. Never given to a session.
+    pub const SERVER: Self = Self(0);
+}
+
+/// Secret that lets a client resume its session. Only ever sent to that client, in HelloReply.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, derive_more::Debug)]
+#[debug("ResumeToken(..)")]
+pub struct ResumeToken(pub [u8; 16]);
 
 pub struct UserSession{
     pub id: SessionId,
+    pub token: ResumeToken,
     pub username: String,
     pub raised_hand: bool,
+    pub role: Role,
+    /// Can't chat or change name until then
+    pub muted_until: Option<Instant>,
+    /// Where the client is connecting from, as seen by the webserver
+    pub address: String,
+    /// Rate limits and repeat detection for chat
+    pub chat: ChatLimiter,
+    /// Wrong host passphrases it gave
+    pub claims: ClaimAttempts,
 }
 impl UserSession{
-    pub fn new()->Self{
-        lazy_static!{
-            static ref ID_GEN: Mutex<UUIDGen> = UUIDGen::new_now().into();
-        }
-        let id = ID_GEN.lock().unwrap().next();
-        Self { id: SessionId(id), username: Self::get_username_for_id(id), raised_hand: false }
+    pub fn new(address: String)->Self{
+        // Both come from the OS CSPRNG so that seeing some ids doesn't let anyone predict others
+        let id = loop{
+            let id = OsRng.next_u64();
+            if id != SessionId::SERVER.0 { break id; }
+        };
+        let mut token = [0; 16];
+        OsRng.fill_bytes(&mut token);
+        Self { id: SessionId(id), token: ResumeToken(token), username: Self::get_username_for_id(id), raised_hand: false, role: Role::default(), muted_until: None, address, chat: ChatLimiter::new(), claims: ClaimAttempts::default() }
+    }
+    pub fn can(&self, permission: Permission)->bool{
+        self.role >= permission.min_role()
+    }
+    pub fn muted(&self)->bool{
+        self.muted_until.is_some_and(|x| Instant::now() < x)
+    }
+    /// The client's IP address, if `address` has one. IPv4 addresses mapped into IPv6 are given as IPv4.
+    pub fn ip(&self)->Option<IpAddr>{
+        self.address.parse::<SocketAddr>().ok().map(|x| x.ip().to_canonical())
     }
     pub fn get_username_for_id(id: u64)->String{
-        let usernames = ["Abiu","Akebi","Ackee","African","American","Apple","Apricot","Aratiles","Araza","Avocado","Banana","Bilberry","Blackberry","Blackcurrant","Blueberry","Boysenberry","Breadfruit","Cactus","Canistel","Catmon","Cempedak","Cherimoya","Cherry","Chico","Citron","Cloudberry","Coco","Coconut","Crab","Cranberry","Currant","Damson","Date","Dragonfruit","Durian","Elderberry","Feijoa","Fig","Finger","Gac","Goji","Gooseberry","Grape","Raisin","Grapefruit","Grewia","Guava","Hala","Haws,","Honeyberry","Huckleberry","Jabuticaba","Jackfruit","Jambul","Japanese","Jostaberry","Jujube","Juniper","Kaffir","Kiwano","Kiwifruit","Kumquat","Lanzones","Lemon","Lime","Loganberry","Longan","Loquat","Lulo","Lychee","Magellan","Macopa","Mamey","Mamey","Mango","Mangosteen","Marionberry","Medlar","Melon","Cantaloupe","Galia","Honeydew","Mouse","Muskmelon","Watermelon","Miracle","Momordica","Monstera","Mulberry","Nance","Nectarine","Orange","Blood","Clementine","Mandarine","Tangerine","Papaya","Passionfruit","Pawpaw","Peach","Pear","Persimmon","Plantain","Plum","Prune","Pineapple","Pineberry","Plumcot","Pomegranate","Pomelo","Quince","Raspberry","Salmonberry","Rambutan","Redcurrant","Rose","Salal","Salak","Santol","Sapodilla","Sapote","Sarguelas","Satsuma","Sloe","Soursop","Star","Strawberry","Sugar","Suriname","Tamarillo","Tamarind","Tangelo","Tayberry","Thimbleberry","Ugli","White","Ximenia","Yuzu"];
+        let usernames = &config::get().usernames;
         let idx = id / 10_u64.pow(3);
         let digits = id % 10_u64.pow(3);
         let idx = idx % usernames.len() as u64;
 
-        let name = usernames[idx as usize];
+        let name = &usernames[idx as usize];
         let name = format!("{}{:03}", name, digits);
         return name;
     }
-}
\ No newline at end of file
+}
+/// Wrong host passphrases given recently, by a session or from an address
+pub struct ClaimAttempts{
+    failed: u32,
+    // When the last one was given
+    last: Instant,
+}
+impl Default for ClaimAttempts{
+    fn default()->Self{
+        Self{ failed: 0, last: Instant::now() }
+    }
+}
+impl ClaimAttempts{
+    // How long until another can be tried, if out of attempts. Forgets them once the cooldown has passed.
+    fn wait(&mut self, now: Instant)->Option<Duration>{
+        let since = now - self.last;
+        if since >= CLAIM_COOLDOWN { self.failed = 0; }
+        return fi!(self.failed >= CLAIM_ATTEMPTS, Some(CLAIM_COOLDOWN - since), None);
+    }
+    fn fail(&mut self, now: Instant){
+        self.wait(now);
+        self.failed += 1;
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn with_role(role: Role)->UserSession{
+        let mut user = UserSession::new("127.0.0.1:5000".into());
+        user.role = role;
+        return user;
+    }
+
+    #[test]
+    fn spectators_cant_manage_rooms(){
+        let spectator = with_role(Role::Spectator);
+        for permission in [Permission::Chat, Permission::Rename, Permission::Buttons, Permission::ManageRooms, Permission::SkipSlowMode] {
+            assert!(!spectator.can(permission), "{permission:?}");
+        }
+        assert!(with_role(Role::Member).can(Permission::ManageRooms));
+    }
+
+    #[test]
+    fn only_moderators_and_up_skip_slow_mode(){
+        assert!(!with_role(Role::Member).can(Permission::SkipSlowMode));
+        assert!(with_role(Role::Moderator).can(Permission::SkipSlowMode));
+        assert!(with_role(Role::Host).can(Permission::SkipSlowMode));
+    }
+}
@@ -1,80 +1,392 @@
+use std::{collections::HashMap, sync::{Arc, Weak}, time::Duration};
+
 use bytes::Bytes;
-use derive_new::new;
-use just_webrtc::{platform::{Channel, PeerConnection}, types::PeerConnectionState, DataChannelExt, PeerConnectionExt};
-use just_webrtc::platform::Error as WebRTCError;
+use futures::future::BoxFuture;
+use lazy_static::lazy_static;
 use log::info;
-use tokio::signal::ctrl_c;
-use packets::{PktC2S, PktS2C_HelloReply};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify, RwLock};
+use webrtc::{data::{data_channel::{Config as ChannelConfig, DataChannel}, message::message_channel_open::ChannelType}, data_channel::RTCDataChannel, peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection}};
+use webrtc::Error as WebRTCError;
+use packets::{Capabilities, PktC2S, PktC2S_Hello, PktC2Sid, PktS2C_HelloReject, PktS2C_HelloReply, PktS2C_Kicked, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+use crate::{bans, chatroom::{Lobby, ROOMS}, config, names, packets::{self, Encode}, shutdown, usersession::{self, ActiveSession, SharedUser, UserSession}};
+
+/// How long closing a connection waits for what's already been sent to go out
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Messages a channel holds for the session before it stops reading, leaving the client to wait
+const CHANNEL_QUEUE: usize = 64;
+/// Largest message a channel can receive
+const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Ordered and reliable. For status and data transfer.
+pub const RELIABLE: ChannelSpec = ChannelSpec{ label: "ro", ordered: true, max_retransmits: None, max_packet_lifetime: None, required: true };
+/// Unordered and never resent. For rapidly changing info e.g.: keypresses, rng seeds.
+pub const UNRELIABLE: ChannelSpec = ChannelSpec{ label: "uu", ordered: false, max_retransmits: Some(0), max_packet_lifetime: None, required: true };
+
+/// Takes what arrives over a registered channel. An error closes the channel, but leaves the session be.
+pub type ChannelHandler = for<'a> fn(&'a ActiveSession, Bytes)->BoxFuture<'a, Result<(),()>>;
+
+lazy_static!{
+    // RELIABLE and UNRELIABLE carry packets, so have no handler
+    static ref CHANNELS: std::sync::RwLock<Vec<(ChannelSpec, Option<ChannelHandler>)>> = std::sync::RwLock::new(vec![(RELIABLE, None), (UNRELIABLE, None)]);
+}
+
+/// A data channel clients can open, and how it delivers what's sent over it.
+/// The client has to open it with the same settings, or it's refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelSpec{
+    pub label: &'static str,
+    pub ordered: bool,
+    /// Times a message is resent before giving up on it. None to keep trying.
+    pub max_retransmits: Option<u16>,
+    /// Milliseconds a message is resent for before giving up on it. None to keep trying. Can't be used with `max_retransmits`.
+    pub max_packet_lifetime: Option<u16>,
+    /// Has to be open before the session starts, and ends the session if it closes.
+    /// Others can be opened at any time, and come and go as they please.
+    pub required: bool,
+}
+impl ChannelSpec{
+    // Whether the client opened the channel with these settings
+    fn matches(&self, config: &ChannelConfig)->bool{
+        use ChannelType::*;
+        let limit = Some(config.reliability_parameter);
+        let (ordered, retransmits, lifetime) = match config.channel_type{
+            Reliable => (true, None, None),
+            ReliableUnordered => (false, None, None),
+            PartialReliableRexmit => (true, limit, None),
+            PartialReliableRexmitUnordered => (false, limit, None),
+            PartialReliableTimed => (true, None, limit),
+            PartialReliableTimedUnordered => (false, None, limit),
+        };
+        return ordered == self.ordered && retransmits == self.max_retransmits.map(u32::from) && lifetime == self.max_packet_lifetime.map(u32::from);
+    }
+}
+
+/// Adds a channel clients can open, with what handles the data it carries, replacing any with the same label.
+/// Panics if it sets both reliability limits. Connections already made keep the channels they had.
+pub fn register(spec: ChannelSpec, handler: ChannelHandler){
+    assert!(spec.max_retransmits.is_none() || spec.max_packet_lifetime.is_none(), "Data channel {:?} can limit retransmits or lifetime, not both", spec.label);
+    let mut channels = CHANNELS.write().unwrap();
+    channels.retain(|x| x.0.label != spec.label);
+    channels.push((spec, Some(handler)));
+}
 
-use crate::{packets::{self, Encode}, usersession::ActiveSession};
+/// Labels of the channels a connection needs before its session can start
+pub fn required_channels()->Vec<&'static str>{
+    return CHANNELS.read().unwrap().iter().filter(|x| x.0.required).map(|x| x.0.label).collect();
+}
 
-/// Abstracts the WebRTC peer under a pseudo-"protocol" of unordered+unreliable or ordered+reliable streams
-/// The reliable streams are for status and data transfer
-/// The unreliable stream is for rapidly changing info e.g.: keypresses, rng seeds
-#[derive(new)]
+/// Checks each channel the client opens against the registry, passing on those that open and match.
+/// Each is checked on its own, so a slow one doesn't hold up the rest. Ends with the peer.
+pub fn accept_all(mut incoming: mpsc::UnboundedReceiver<PendingChannel>)->mpsc::UnboundedReceiver<Channel>{
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move{
+        while let Some(pending) = incoming.recv().await {
+            let tx = tx.clone();
+            tokio::spawn(async move{
+                let Some(channel) = accept(pending).await else { return };
+                // Nobody's waiting for it anymore
+                if let Err(x) = tx.send(channel) { x.0.close().await; }
+            });
+        }
+    });
+    return rx;
+}
+
+// Waits for a channel to open, then checks it against the registry.
+// Channels that aren't registered, or don't match, are closed. None if it's refused or never opens.
+async fn accept(pending: PendingChannel)->Option<Channel>{
+    let PendingChannel{ inner, opened } = pending;
+    let entry = CHANNELS.read().unwrap().iter().find(|x| x.0.label == inner.label()).copied();
+    let Some((spec, handler)) = entry else {
+        info!("Refused unknown data channel {:?}", inner.label());
+        let _ = inner.close().await;
+        return None;
+    };
+    let Ok(Ok(dc)) = tokio::time::timeout(config::get().connect_timeout(), opened).await else {
+        let _ = inner.close().await;
+        return None;
+    };
+    if !spec.matches(&dc.config) {
+        info!("Refused data channel {:?} as its settings {:?} don't match {:?}", spec.label, dc.config, spec);
+        let _ = inner.close().await;
+        return None;
+    }
+    return Some(Channel::new(spec, handler, inner, dc));
+}
+
+// Open channels by label
+type OpenChannels = Arc<std::sync::RwLock<HashMap<&'static str, Arc<Channel>>>>;
+
+/// Abstracts the WebRTC peer under a set of data channels, each delivering what's sent over it as its `ChannelSpec` says.
+/// RELIABLE and UNRELIABLE are always open. Any others in the registry can be opened by the client whenever it likes.
 pub struct ClientConnection{
-    peer: PeerConnection,
-    chanr: Channel, // Reliable+Ordered channel
-    chanu: Channel  // Unreliable+Unordered channel
+    peer: Arc<RTCPeerConnection>,
+    channels: OpenChannels,
+    // Told when a channel opens, so `recv` listens on it too
+    opened: Arc<Notify>,
+    // Every state the peer goes through, from `on_peer_connection_state_change`
+    states: Mutex<mpsc::UnboundedReceiver<RTCPeerConnectionState>>,
 }
 
 pub enum RecvError{
+    /// The server is shutting down
     Abort,
-    WebRTCError(WebRTCError),
+    /// A channel was closed
+    Closed,
+}
+
+/// Something that came over a channel, sorted by what the channel carries
+pub enum Incoming{
+    /// From RELIABLE or UNRELIABLE
+    Packet(ChannelSpec, PktC2S),
+    /// From RELIABLE or UNRELIABLE, but not a packet we know
+    Malformed(ChannelSpec, Bytes),
+    /// From any other channel, for its handler
+    Data(ChannelSpec, ChannelHandler, Bytes),
 }
 
 impl ClientConnection{
-    /// Does not destinguish between reliable and unreliable channels.
-    pub async fn recv(&self)->Result<Bytes, RecvError>{
+    /// `open` has the required channels. Channels the client opens later on come through `accepted`, from `accept_all`.
+    pub fn new(
+        peer: Arc<RTCPeerConnection>,
+        open: HashMap<&'static str, Arc<Channel>>,
+        mut accepted: mpsc::UnboundedReceiver<Channel>,
+        states: mpsc::UnboundedReceiver<RTCPeerConnectionState>,
+    )->Self{
+        let channels: OpenChannels = Arc::new(std::sync::RwLock::new(open));
+        let opened = Arc::new(Notify::new());
+        let (channels2, opened2) = (channels.clone(), opened.clone());
+        // Ends with the peer
+        tokio::spawn(async move{
+            while let Some(channel) = accepted.recv().await {
+                if channels2.read().unwrap().contains_key(channel.spec.label) {
+                    info!("Refused a second data channel {:?}", channel.spec.label);
+                    channel.close().await;
+                    continue;
+                }
+                channels2.write().unwrap().insert(channel.spec.label, Arc::new(channel));
+                opened2.notify_one();
+            }
+        });
+        return Self{ peer, channels, opened, states: Mutex::new(states) };
+    }
+    /// The next thing to arrive over any open channel
+    pub async fn recv(&self)->Result<Incoming, RecvError>{
         use RecvError::*;
-        tokio::select!{
-            x = self.chanr.receive() => { return x.map_err(|e|WebRTCError(e)); },
-            x = self.chanu.receive() => { return x.map_err(|e|WebRTCError(e)); },
-            _ = ctrl_c() => { return Err(Abort); }
+        loop{
+            let open: Vec<Arc<Channel>> = self.channels.read().unwrap().values().cloned().collect();
+            if open.is_empty() { return Err(Closed); }
+            tokio::select!{
+                (x, i, _) = futures::future::select_all(open.iter().map(|x| Box::pin(x.recv()))) => match x{
+                    Some(data) => return Ok(open[i].sort(data)),
+                    None if open[i].spec.required => return Err(Closed),
+                    None => { self.channels.write().unwrap().remove(open[i].spec.label); },
+                },
+                _ = self.opened.notified() => {}, // Listen on the new one too
+                _ = shutdown::requested() => { return Err(Abort); }
+            }
         }
     }
-    /// usize = bytes sent
+    /// Whether the client has the channel open
+    pub fn is_open(&self, spec: &ChannelSpec)->bool{
+        return self.channels.read().unwrap().contains_key(spec.label);
+    }
+    /// Sends a packet over the reliable channel. usize = bytes sent
     pub async fn send(&self, data: impl Into<Bytes>)->Result<usize, WebRTCError>{
-        let data = data.into();
-        // info!("{} <+ {:?}", "Out", data);
-        self.chanr.send(&data).await
+        self.send_on(&RELIABLE, data).await
+    }
+    /// Sends a packet over RELIABLE or UNRELIABLE. Errors for any other channel, as the client only decodes packets from those.
+    pub async fn send_packet(&self, spec: &ChannelSpec, pkt: impl Encode)->Result<usize, WebRTCError>{
+        if *spec != RELIABLE && *spec != UNRELIABLE { return Err(WebRTCError::new(format!("Data channel {:?} doesn't carry packets", spec.label))); }
+        self.send_on(spec, pkt.encode()).await
+    }
+    /// Sends over a particular channel. Errors if the client doesn't have it open.
+    pub async fn send_on(&self, spec: &ChannelSpec, data: impl Into<Bytes>)->Result<usize, WebRTCError>{
+        let channel = self.channels.read().unwrap().get(spec.label).cloned();
+        let Some(channel) = channel else { return Err(WebRTCError::ErrDataChannelNotOpen) };
+        channel.send(data).await
+    }
+    /// Closes a channel, leaving the rest. The session ends if it's required.
+    pub async fn close_channel(&self, spec: &ChannelSpec){
+        let channel = self.channels.read().unwrap().get(spec.label).cloned();
+        // `recv` lets go of it once its queue runs dry
+        if let Some(channel) = channel { channel.close().await; }
+    }
+    pub async fn state_change(&self)->RTCPeerConnectionState{
+        // The sender lives as long as the peer
+        self.states.lock().await.recv().await.unwrap_or(RTCPeerConnectionState::Closed)
     }
-    pub async fn send_unreliable(&self, data: impl Into<Bytes>)->Result<usize, WebRTCError>{
-        let data = data.into();
-        // info!("{} <? {:?}", "Out", data);
-        self.chanu.send(&data).await
+    /// Lets what's been sent go out, then closes every channel and the peer.
+    pub async fn close(&self){
+        let open: Vec<Arc<Channel>> = self.channels.read().unwrap().values().cloned().collect();
+        for channel in &open { channel.flush(FLUSH_TIMEOUT).await; }
+        for channel in &open { channel.close().await; }
+        let _ = self.peer.close().await;
     }
-    pub async fn state_change(&self)->PeerConnectionState{
-        self.peer.state_change().await
+}
+
+/// A data channel the client has announced, waiting to open
+pub struct PendingChannel{
+    inner: Arc<RTCDataChannel>,
+    opened: oneshot::Receiver<Arc<DataChannel>>,
+}
+impl PendingChannel{
+    /// Call as soon as the remote announces the channel, so it's detached as it opens
+    pub fn new(inner: Arc<RTCDataChannel>)->Self{
+        let (tx, opened) = oneshot::channel();
+        // Weak, as the channel holds on to its handler until it opens
+        let weak: Weak<RTCDataChannel> = Arc::downgrade(&inner);
+        inner.on_open(Box::new(move || Box::pin(async move{
+            let Some(inner) = weak.upgrade() else { return };
+            if let Ok(dc) = inner.detach().await { let _ = tx.send(dc); }
+        })));
+        return Self{ inner, opened };
+    }
+}
+
+/// An open data channel, with what it receives queued up
+pub struct Channel{
+    spec: ChannelSpec,
+    // None for channels that carry packets
+    handler: Option<ChannelHandler>,
+    inner: Arc<RTCDataChannel>,
+    rx: Mutex<mpsc::Receiver<Bytes>>,
+}
+impl Channel{
+    fn new(spec: ChannelSpec, handler: Option<ChannelHandler>, inner: Arc<RTCDataChannel>, dc: Arc<DataChannel>)->Self{
+        let (tx, rx) = mpsc::channel(CHANNEL_QUEUE);
+        // The queue ends when the channel closes. While it's full, the client is made to wait.
+        tokio::spawn(async move{
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            loop{
+                let data = match dc.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => Bytes::copy_from_slice(&buf[..n]),
+                };
+                if tx.send(data).await.is_err() { break; }
+            }
+        });
+        return Self{ spec, handler, inner, rx: Mutex::new(rx) };
+    }
+    pub fn spec(&self)->ChannelSpec{
+        self.spec
+    }
+    // None once the channel has closed
+    async fn recv(&self)->Option<Bytes>{
+        self.rx.lock().await.recv().await
+    }
+    // Decodes packets, or picks out the handler
+    fn sort(&self, data: Bytes)->Incoming{
+        if let Some(handler) = self.handler { return Incoming::Data(self.spec, handler, data); }
+        match packets::decode(data.to_vec()) {
+            Ok(pkt) => Incoming::Packet(self.spec, pkt),
+            Err(_) => Incoming::Malformed(self.spec, data),
+        }
+    }
+    // usize = bytes sent
+    async fn send(&self, data: impl Into<Bytes>)->Result<usize, WebRTCError>{
+        self.inner.send(&data.into()).await
+    }
+    pub async fn close(&self){
+        let _ = self.inner.close().await;
+    }
+    // Waits for anything still buffered to be sent
+    async fn flush(&self, timeout: Duration){
+        let _ = tokio::time::timeout(timeout, async{
+            while self.inner.buffered_amount().await > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await;
     }
 }
 
 /// Awaiting this function will block until the connection is closed.
-pub async fn manage_connection(conn: ClientConnection){
-    // FIXME: Invalid connections that are "Drop"ped are not actually closed.
-    // The client is still connected in the underlying network layer - but nothing can be exchanged.
-    // The channel is still open too.
-    // This appears to be a just_webrtc issue?
+/// `source` is the client's address, as seen by the webserver.
+pub async fn manage_connection(conn: ClientConnection, source: String){
+    match introduce(&conn, source).await {
+        // We defer to the session handler, which closes the connection when it's done
+        Some((caps, user, room)) => ActiveSession::new(conn, caps, user).handle_active_session(room).await,
+        None => { info!("Drop"); conn.close().await; }
+    }
+}
 
+// Gets the client through Hello. None if it didn't work out.
+async fn introduce(conn: &ClientConnection, source: String)->Option<(Capabilities, SharedUser, Arc<Lobby>)>{
     // Step 1: Client needs to send a Hello message to introduce itself.
     // Anything else breaks the link.
-    let Some(_msg) = conn.recv().await
-        .ok() // Received a message (e.g.: connection didn't fail)
-        .and_then(|x| packets::decode(x.to_vec()).ok()) // Is a valid packet
-        .and_then(|x| match x { PktC2S::Hello(p) => Some(p), _ => None }) // Is Hello
-        else { info!("Drop"); return; };
+    let Ok(msg) = conn.recv().await else { return None; };
+    let hello = match msg {
+        Incoming::Packet(_, PktC2S::Hello(p)) => p,
+        // A Hello that doesn't parse comes from a client that predates protocol versioning
+        Incoming::Malformed(_, msg) if msg.first() == Some(&(PktC2Sid::Hello as u8)) => PktC2S_Hello{ version: 0, caps: Capabilities::NONE, resume: None },
+        _ => return None,
+    };
+
+    // Step 2: Make sure we speak the same protocol, and agree on the optional features
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.version) {
+        info!("Rejecting client speaking protocol version {} (supported: {}-{})", hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+        let reason = format!("This page is out of date for the server (protocol version {}, server requires {}). Please reload the page.", hello.version, PROTOCOL_VERSION);
+        let _ = conn.send(PktS2C_HelloReject::new(PROTOCOL_VERSION, reason).encode()).await;
+        return None;
+    }
+    let caps = hello.caps & Capabilities::SERVER;
 
-    // Step 2: We create a session
-    // TODO: SessionId session recovery
-    let session = ActiveSession::new(conn);
+    // Step 3: We resume the client's session if we're still holding it, else create a new one
+    let resumed = match hello.resume {
+        Some(token) => usersession::resume_session(token).await,
+        None => None,
+    };
+    let (user, room) = match resumed {
+        Some(x) => x,
+        None => {
+            let mut user = UserSession::new(source.clone());
+            user.username = names::default_name(user.id).await;
+            (Arc::new(RwLock::new(user)), ROOMS.default_room())
+        }
+    };
+    user.write().await.address = source;
+    // Bans on the address were checked at /connect, but a resumed session could be banned by id or name
+    let banned = bans::session_banned(&*user.read().await);
+    if let Some(reason) = banned {
+        let sid = user.read().await.id;
+        room.remove(sid).await;
+        let _ = conn.send(PktS2C_Kicked::new(format!("Banned: {}", reason)).encode()).await;
+        return None;
+    }
 
-    // Step 3: We send HelloReply
+    // Step 4: We send HelloReply
     let reply = {
-        let lock = session.user.read().await;
-        PktS2C_HelloReply::new(lock.id, lock.username.clone())
+        let lock = user.read().await;
+        PktS2C_HelloReply::new(lock.id, lock.token, PROTOCOL_VERSION, caps, lock.username.clone())
     };
-    let Ok(_) = session.send(reply.encode()).await else { info!("Drop"); return; };
+    conn.send(reply.encode()).await.ok()?;
+    return Some((caps, user, room));
+}
 
-    // Step 4: We defer to the session handler
-    session.handle_active_session().await;
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn opened_as(channel_type: ChannelType, reliability_parameter: u32)->ChannelConfig{
+        ChannelConfig{ channel_type, reliability_parameter, ..Default::default() }
+    }
+
+    #[test]
+    fn no_limit_is_not_a_limit_of_zero(){
+        assert!(RELIABLE.matches(&opened_as(ChannelType::Reliable, 0)));
+        assert!(!RELIABLE.matches(&opened_as(ChannelType::PartialReliableRexmit, 0)));
+        assert!(UNRELIABLE.matches(&opened_as(ChannelType::PartialReliableRexmitUnordered, 0)));
+        assert!(!UNRELIABLE.matches(&opened_as(ChannelType::ReliableUnordered, 0)));
+        assert!(!UNRELIABLE.matches(&opened_as(ChannelType::PartialReliableTimedUnordered, 0)));
+    }
+
+    #[test]
+    fn limits_and_order_must_match(){
+        let timed = ChannelSpec{ label: "t", ordered: true, max_retransmits: None, max_packet_lifetime: Some(500), required: false };
+        assert!(timed.matches(&opened_as(ChannelType::PartialReliableTimed, 500)));
+        assert!(!timed.matches(&opened_as(ChannelType::PartialReliableTimed, 400)));
+        assert!(!timed.matches(&opened_as(ChannelType::PartialReliableTimedUnordered, 500)));
+        assert!(!timed.matches(&opened_as(ChannelType::PartialReliableRexmit, 500)));
+    }
+}
@@ -1,73 +1,255 @@
-use std::time::Duration;
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, OnceLock}, time::{Duration, Instant}};
 
-use just_webrtc::{platform::{Channel, PeerConnection}, types::{ICECandidate, PeerConfiguration, PeerConnectionState, SessionDescription}, DataChannelExt, PeerConnectionBuilder, PeerConnectionExt};
+use lazy_static::lazy_static;
 use log::info;
 use serde::Serialize;
+use tokio::{net::UdpSocket, sync::{mpsc, oneshot}};
+use webrtc::{
+    api::{setting_engine::SettingEngine, APIBuilder, API},
+    ice::{network_type::NetworkType, udp_mux::{UDPMuxDefault, UDPMuxParams}, udp_network::{EphemeralUDP, UDPNetwork}},
+    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
+    peer_connection::{configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState, policy::ice_transport_policy::RTCIceTransportPolicy, sdp::session_description::RTCSessionDescription, RTCPeerConnection},
+};
 
-use crate::{webrtcpeer, ClientConnection};
+use crate::{config::{self, IceCandidates}, fi, shutdown, webrtcpeer::{self, PendingChannel, ClientConnection}};
 
-// The lifetime of a connection accept response.
-// The amount of time for web client to establish a webrtc connection with us, after using `/connect`
-const REMOTE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static!{
+    // Offers we've answered that haven't connected or timed out yet: where from, and since when
+    static ref PENDING_OFFERS: Mutex<HashMap<u64, (String, Instant)>> = Mutex::new(HashMap::new());
+}
+static NEXT_OFFER: AtomicU64 = AtomicU64::new(0);
+// Which UDP ports peers use, and the one address they gather on when sharing a port. Set up once by `init_udp`.
+static UDP_NETWORK: OnceLock<(UDPNetwork, Option<IpAddr>)> = OnceLock::new();
+
+/// Sets up the UDP ports peers use, binding the shared port if there is one. Describes the choice for the log.
+pub async fn init_udp()->Result<String, String>{
+    let config = config::get();
+    let (network, only_ip, description) = if let Some(port) = config.ice_udp_port {
+        let addr = SocketAddr::from((config.host, port));
+        let socket = UdpSocket::bind(addr).await.map_err(|e| format!("Could not bind ICE UDP port {} ({e})", addr))?;
+        // Every candidate on a shared port reads from the same connection, which only works with one of them.
+        // So it's the address bound to, or the LAN address when bound to all of them.
+        let ip = match config.host.is_unspecified() {
+            true => local_ip_address::local_ip().map_err(|e| format!("Could not find the LAN address to share ICE UDP port {} on ({e}). Set host to it.", port))?,
+            false => config.host,
+        };
+        (UDPNetwork::Muxed(UDPMuxDefault::new(UDPMuxParams::new(socket))), Some(ip), format!("ICE sharing UDP port {} between every peer, on {}", port, ip))
+    }else if let (Some(min), Some(max)) = (config.ice_port_min, config.ice_port_max) {
+        let range = EphemeralUDP::new(min, max).map_err(|e| format!("Invalid ICE port range ({e})"))?;
+        (UDPNetwork::Ephemeral(range), None, format!("ICE using UDP ports {}-{}", min, max))
+    }else{
+        (UDPNetwork::default(), None, "ICE using any free UDP ports".to_owned())
+    };
+    let _ = UDP_NETWORK.set((network, only_ip));
+    return Ok(description);
+}
+
+/// Answered offers still waiting for the client to connect, oldest first: the client's address and how long it's been
+pub fn pending_offers()->Vec<(String, Duration)>{
+    let mut offers: Vec<(String, Duration)> = PENDING_OFFERS.lock().unwrap().values().map(|(source, since)| (source.clone(), since.elapsed())).collect();
+    offers.sort_by_key(|x| std::cmp::Reverse(x.1));
+    return offers;
+}
 
 #[derive(Serialize)]
 pub struct SessionTuple{
-    description: SessionDescription,
-    candidates: Vec<ICECandidate>,
+    description: RTCSessionDescription,
+    candidates: Vec<RTCIceCandidateInit>,
 }
 
 /// Attempts to create a WebRTC answer for the given inputs. If the inputs are malformed, you'll get an error back.
-pub async fn create_answer(offer: SessionDescription, connectionsource: String) -> Result<SessionTuple, ()>{
-    let Ok(remote_peer_connection) = PeerConnectionBuilder::new()
-        .set_config(PeerConfiguration{..Default::default()})
-        .with_remote_offer(Some(offer)).map_err(|_|())?
-        .build().await else { return Err(()) };
-    // remote_peer_connection.add_ice_candidates(offer.sdp_type).await?;
-    let Some(answer) = remote_peer_connection.get_local_description().await else{ return Err(()) };
-    let candidates = remote_peer_connection.collect_ice_candidates().await.unwrap_or_default();
-    // info!("Incoming: {:?}\n\tMy Response: {:?}\n\tCandidates: {:?}", remote_peer_connection, answer.sdp, candidates);
+/// Waits for every local candidate to be gathered, so they can all go in the one response.
+/// `public_ip` is advertised in place of the server's own address, if given.
+pub async fn create_answer(offer: RTCSessionDescription, connectionsource: String, public_ip: Option<IpAddr>) -> Result<SessionTuple, ()>{
+    let (pending, answer) = answer_offer(offer, public_ip).await?;
+    let mut candidates = vec![];
+    let mut candidates_rx = pending.candidates;
+    while let Some(Some(candidate)) = candidates_rx.recv().await {
+        candidates.push(candidate);
+    }
+    // info!("Incoming: {:?}\n\tMy Response: {:?}\n\tCandidates: {:?}", peer, answer.sdp, candidates);
+    host(pending.peer, pending.states, pending.channels, connectionsource, None);
+    return Ok(SessionTuple{description: answer, candidates});
+}
 
+/// An answer given straight away, for trickle ICE.
+/// Local candidates come through `candidates` as they're gathered, and the client's are added with `add_candidate`.
+pub struct TrickleAnswer{
+    pub description: RTCSessionDescription,
+    /// None once gathering is complete
+    pub candidates: mpsc::UnboundedReceiver<Option<RTCIceCandidateInit>>,
+    /// Completes once the client has connected or been given up on. Nothing more needs signalling after that.
+    pub finished: oneshot::Receiver<()>,
+    peer: Arc<RTCPeerConnection>,
+}
+impl TrickleAnswer{
+    /// Adds a candidate the client gathered
+    pub async fn add_candidate(&self, candidate: RTCIceCandidateInit)->Result<(), ()>{
+        return self.peer.add_ice_candidate(candidate).await.map_err(|_| ());
+    }
+}
+
+/// Like `create_answer`, but doesn't wait for candidates to be gathered
+pub async fn create_trickle_answer(offer: RTCSessionDescription, connectionsource: String, public_ip: Option<IpAddr>) -> Result<TrickleAnswer, ()>{
+    let (pending, description) = answer_offer(offer, public_ip).await?;
+    let (finished_tx, finished) = oneshot::channel();
+    host(pending.peer.clone(), pending.states, pending.channels, connectionsource, Some(finished_tx));
+    return Ok(TrickleAnswer{ description, candidates: pending.candidates, finished, peer: pending.peer });
+}
+
+// A peer with its events hooked up, waiting on the client
+struct PendingPeer{
+    peer: Arc<RTCPeerConnection>,
+    states: mpsc::UnboundedReceiver<RTCPeerConnectionState>,
+    channels: mpsc::UnboundedReceiver<PendingChannel>,
+    // None marks the end of gathering
+    candidates: mpsc::UnboundedReceiver<Option<RTCIceCandidateInit>>,
+}
+
+// The ICE servers and policy from the config
+fn rtc_configuration()->RTCConfiguration{
+    let config = config::get();
+    let ice_servers = config.ice_servers.iter()
+        .map(|x| RTCIceServer{ urls: x.urls.clone(), username: x.username.clone(), credential: x.credential.clone(), ..Default::default() })
+        .collect();
+    let ice_transport_policy = match config.ice_candidates{
+        IceCandidates::Relay => RTCIceTransportPolicy::Relay,
+        IceCandidates::All | IceCandidates::Host => RTCIceTransportPolicy::All,
+    };
+    return RTCConfiguration{ ice_servers, ice_transport_policy, ..Default::default() };
+}
+
+// Candidate gathering settings from the config that aren't part of RTCConfiguration.
+// Data channels are read directly, to check how they were opened and to queue what they receive.
+fn api(public_ip: Option<IpAddr>)->API{
+    let config = config::get();
+    let mut settings = SettingEngine::default();
+    settings.detach_data_channels();
+    // Lite agents only gather host candidates
+    settings.set_lite(config.ice_candidates == IceCandidates::Host);
+    if !config.ice_interfaces.is_empty() || !config.ice_interfaces_deny.is_empty() {
+        settings.set_interface_filter(Box::new(|name| config::get().ice_interface_allowed(name)));
+    }
+    if let Some((network, only_ip)) = UDP_NETWORK.get() {
+        settings.set_udp_network(network.clone());
+        if let Some(ip) = *only_ip { settings.set_ip_filter(Box::new(move |x| x == ip)); }
+    }
+    if let Some(ip) = public_ip {
+        // Every host candidate gets the public address. Ones of the other IP version would have nothing to map to.
+        settings.set_nat_1to1_ips(vec![ip.to_string()], RTCIceCandidateType::Host);
+        settings.set_network_types(vec![fi!(ip.is_ipv4(), NetworkType::Udp4, NetworkType::Udp6)]);
+    }
+    return APIBuilder::new().with_setting_engine(settings).build();
+}
+
+// Makes a peer for the offer and answers it. Gathering starts once this returns.
+async fn answer_offer(offer: RTCSessionDescription, public_ip: Option<IpAddr>) -> Result<(PendingPeer, RTCSessionDescription), ()>{
+    let Ok(peer) = api(public_ip).new_peer_connection(rtc_configuration()).await else { return Err(()) };
+    let peer = Arc::new(peer);
+
+    // Hook everything up before anything can happen
+    let (states_tx, states) = mpsc::unbounded_channel();
+    peer.on_peer_connection_state_change(Box::new(move |state| {
+        let _ = states_tx.send(state);
+        Box::pin(async{})
+    }));
+    let (channels_tx, channels) = mpsc::unbounded_channel();
+    peer.on_data_channel(Box::new(move |channel| {
+        let _ = channels_tx.send(PendingChannel::new(channel));
+        Box::pin(async{})
+    }));
+    let (candidates_tx, candidates) = mpsc::unbounded_channel();
+    peer.on_ice_candidate(Box::new(move |candidate| {
+        // None marks the end of gathering
+        let _ = candidates_tx.send(candidate.and_then(|x| x.to_json().ok()));
+        Box::pin(async{})
+    }));
+
+    let answer = async{
+        peer.set_remote_description(offer).await?;
+        let answer = peer.create_answer(None).await?;
+        peer.set_local_description(answer).await?;
+        return Ok::<_, webrtc::Error>(());
+    };
+    if answer.await.is_err() {
+        let _ = peer.close().await;
+        return Err(());
+    }
+    let Some(answer) = peer.local_description().await else { let _ = peer.close().await; return Err(()) };
+    return Ok((PendingPeer{ peer, states, channels, candidates }, answer));
+}
+
+// Waits in the background for the client to connect, then runs its session. `finished` is told once it has connected or been given up on.
+fn host(
+    peer: Arc<RTCPeerConnection>,
+    states: mpsc::UnboundedReceiver<RTCPeerConnectionState>,
+    channels: mpsc::UnboundedReceiver<PendingChannel>,
+    connectionsource: String,
+    finished: Option<oneshot::Sender<()>>,
+){
     info!("Hosting offer for {:?}", connectionsource);
+    let offer = NEXT_OFFER.fetch_add(1, Ordering::Relaxed);
+    PENDING_OFFERS.lock().unwrap().insert(offer, (connectionsource.clone(), Instant::now()));
     tokio::spawn(async move{
-        if let Ok(conn) = await_connection(remote_peer_connection).await {
+        // Shutdown waits for this, whether or not the client shows up
+        let _guard = shutdown::hold();
+        let conn = tokio::select!{
+            conn = await_connection(peer.clone(), states, channels) => conn,
+            _ = shutdown::requested() => Err(()),
+        };
+        PENDING_OFFERS.lock().unwrap().remove(&offer);
+        if let Some(finished) = finished { let _ = finished.send(()); }
+        if let Ok(conn) = conn {
             info!("WebRTC established with {:?}", connectionsource);
-            webrtcpeer::manage_connection(conn).await;
+            webrtcpeer::manage_connection(conn, connectionsource).await;
         }else{
             info!("Gave up on offer for {:?}", connectionsource);
+            let _ = peer.close().await;
         }
     });
-    return Ok(SessionTuple{description: answer, candidates});
 }
 
-async fn await_connection(peer: PeerConnection)->Result<ClientConnection, ()>{
-    let Ok(_) = tokio::time::timeout(REMOTE_CONNECTION_TIMEOUT, wait_is_connected(&peer)).await else {
+async fn await_connection(
+    peer: Arc<RTCPeerConnection>,
+    mut states: mpsc::UnboundedReceiver<RTCPeerConnectionState>,
+    channels: mpsc::UnboundedReceiver<PendingChannel>,
+)->Result<ClientConnection, ()>{
+    // The amount of time for web client to establish a webrtc connection with us, after using `/connect`
+    let timeout = config::get().connect_timeout();
+    let Ok(_) = tokio::time::timeout(timeout, wait_is_connected(&mut states)).await else {
         return Err(());
     };
 
-    // Receive ro and uu data channels.
-    let mut ro: Option<Channel> = None;
-    let mut uu: Option<Channel> = None;
-    loop{
-        let Ok(remote_channel) = peer.receive_channel().await else {return Err(())};
-        remote_channel.wait_ready().await;
-        match remote_channel.label().as_str(){
-            "ro" => ro = Some(remote_channel),
-            "uu" => uu = Some(remote_channel),
-            _ => return Err(()) // Unexpected channel
+    // Receive the required data channels. Each is accepted on its own, so none waits on another.
+    let required = webrtcpeer::required_channels();
+    let mut accepted = webrtcpeer::accept_all(channels);
+    let mut open = HashMap::new();
+    let receive = async{
+        while !required.iter().all(|x| open.contains_key(x)) {
+            let Some(channel) = accepted.recv().await else { return Err(()) };
+            if open.contains_key(channel.spec().label) {
+                info!("Refused a second data channel {:?}", channel.spec().label);
+                channel.close().await;
+                continue;
+            }
+            open.insert(channel.spec().label, Arc::new(channel));
         }
-        if ro.is_some() && uu.is_some() { break; }
-    }
+        return Ok(());
+    };
+    let Ok(Ok(_)) = tokio::time::timeout(timeout, receive).await else { return Err(()) };
 
-    let conn = ClientConnection::new(peer, ro.unwrap(), uu.unwrap());
+    // Channels opened from here on are taken care of by the connection
+    let conn = ClientConnection::new(peer, open, accepted, states);
     return Ok(conn);
 }
 
 /// Must be used in conjunction with a timeout
-async fn wait_is_connected(peer: &PeerConnection) -> Result<(),()>{
-    use PeerConnectionState::*;
-    loop{ match peer.state_change().await {
-        Failed | Closed => return Err(()),
-        Connected => return Ok(()),
+async fn wait_is_connected(states: &mut mpsc::UnboundedReceiver<RTCPeerConnectionState>) -> Result<(),()>{
+    use RTCPeerConnectionState::*;
+    loop{ match states.recv().await {
+        Some(Failed | Closed) | None => return Err(()),
+        Some(Connected) => return Ok(()),
         _ => {}
     }}
-}
\ No newline at end of file
+}
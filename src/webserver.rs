@@ -1,35 +1,41 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
 
-use axum::{extract::ConnectInfo, http::{header, HeaderMap, Uri}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
-use just_webrtc::types::SessionDescription;
-use log::info;
+use axum::{extract::{ws::{Message, WebSocket, WebSocketUpgrade}, ConnectInfo}, http::{header, HeaderMap, Uri}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use log::{info, warn};
 use rust_embed_for_web::EmbedableFile;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::net::TcpListener;
+use webrtc::{ice_transport::ice_candidate::RTCIceCandidateInit, peer_connection::sdp::session_description::RTCSessionDescription};
 
-use crate::webrtcsignalling;
+use crate::{bans, chatroom::ROOMS, config, shutdown, webrtcsignalling};
 
-const WEBSERVER_HOST: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const URL_ROOT: &str = "index.html";
 const URL_404: &str = "404.html";
 
-pub async fn webserver_run(port: u16) {
+pub async fn webserver_run() {
+    // Load the rooms and their chat logs now rather than when the first person joins
+    lazy_static::initialize(&ROOMS);
+
     // Serve the web folder with the client in it
     let app = Router::new()
         .route("/", get(serve_root))
         .route("/connect", post(respond_to_webrtc_offer)) // Defers to the signalling subsystem
+        .route("/signal", get(signal_over_websocket)) // The same, trickling candidates rather than waiting on them
         .fallback_service(get(serve_static))
         .into_make_service_with_connect_info::<SocketAddr>();
 
-    let socket = SocketAddr::from((WEBSERVER_HOST, port));
+    let udp = webrtcsignalling::init_udp().await.unwrap(); // Nobody can connect without it, so failure is fatal
+    info!("{}", udp);
+    let port = config::get().port;
+    let socket = SocketAddr::from((config::get().host, port));
     let listener = TcpListener::bind(socket).await.unwrap(); // Failed to bind is a fatal error
     let server = axum::serve(listener, app);
-    let localip = local_ip_address::local_ip().map(|x| x.to_string()).unwrap_or("?".into());
+    let localip = config::get().public_ip.map(Ok).unwrap_or_else(local_ip_address::local_ip).map(|x| x.to_string()).unwrap_or("?".into());
     info!("Webserver listening at {} (localhost: http://127.0.0.1:{port}/, LAN: http://{}:{port}/)", socket, localip);
 
-    async fn shutdown_detector(){ tokio::signal::ctrl_c().await.unwrap() } // Infallible
     server
-        .with_graceful_shutdown(shutdown_detector())
+        .with_graceful_shutdown(shutdown::requested())
         .await.unwrap(); // Run it. Unexpected failure is fatal
 }
 
@@ -84,10 +90,26 @@ impl<T> IntoResponse for StaticFile<T> where T: Into<String>{
   }
 }
 
-async fn respond_to_webrtc_offer(ConnectInfo(addr): ConnectInfo<SocketAddr>, payload: Option<Json<SessionDescription>>)->Json<Value>{
+/// The address to advertise in place of the server's own, if any
+fn public_ip(headers: &HeaderMap)->Option<IpAddr>{
+    let config = config::get();
+    if config.public_ip_from_host {
+        // An IP, with or without a port
+        let host = headers.get(header::HOST).and_then(|x| x.to_str().ok()).unwrap_or_default();
+        let ip = host.parse::<SocketAddr>().map(|x| x.ip()).or_else(|_| host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>());
+        if let Ok(ip) = ip.map(|x| x.to_canonical()) { return Some(ip); }
+    }
+    return config.public_ip;
+}
+
+async fn respond_to_webrtc_offer(ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, payload: Option<Json<RTCSessionDescription>>)->Json<Value>{
+    // Turned away before any WebRTC work is done for them
+    if let Some(reason) = bans::ip_banned(addr.ip()) {
+        info!("Refused banned address {}", addr);
+        return Json(json!({"Banned": reason}));
+    }
     if let Some(params) = payload {
-        let id = format!("{}", addr);
-        let x = webrtcsignalling::create_answer(params.0, id).await;
+        let x = webrtcsignalling::create_answer(params.0, addr.to_string(), public_ip(&headers)).await;
         return match x{
           Ok(x) => Json(json!(x)),
           Err(_) => Json(json!({"Malformed":"The provided WebRTC offer is unusable."})),
@@ -97,6 +119,82 @@ async fn respond_to_webrtc_offer(ConnectInfo(addr): ConnectInfo<SocketAddr>, pay
     }
 }
 
+// What the client sends over /signal: the offer first, then its candidates as it gathers them
+#[derive(Deserialize)]
+enum SignalIn{
+    Offer(Box<RTCSessionDescription>),
+    /// None once the client has gathered them all
+    Candidate(Option<RTCIceCandidateInit>),
+}
+// What the server sends over /signal: the answer, then its candidates. Or why it won't.
+#[derive(Serialize)]
+enum SignalOut{
+    Description(Box<RTCSessionDescription>),
+    /// None once the server has gathered them all
+    Candidate(Option<RTCIceCandidateInit>),
+    Banned(String),
+    Malformed(String),
+}
+
+async fn signal_over_websocket(ws: WebSocketUpgrade, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap)->Response{
+    let public_ip = public_ip(&headers);
+    return ws.on_upgrade(move |socket| trickle_signalling(socket, addr, public_ip));
+}
+
+/// Answers the offer straight away, then swaps candidates with the client as each side gathers them.
+/// The socket is closed once the WebRTC connection is up or has been given up on.
+async fn trickle_signalling(mut socket: WebSocket, addr: SocketAddr, public_ip: Option<IpAddr>){
+    async fn send(socket: &mut WebSocket, msg: SignalOut)->Result<(), ()>{
+        let text = serde_json::to_string(&msg).map_err(|_| ())?;
+        return socket.send(Message::Text(text)).await.map_err(|_| ());
+    }
+    if let Some(reason) = bans::ip_banned(addr.ip()) {
+        info!("Refused banned address {}", addr);
+        let _ = send(&mut socket, SignalOut::Banned(reason)).await;
+        return;
+    }
+    let offer = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str::<SignalIn>(&text).ok(),
+        _ => return, // Left without saying anything
+    };
+    let Some(SignalIn::Offer(offer)) = offer else {
+        let _ = send(&mut socket, SignalOut::Malformed("Expected an offer first.".into())).await;
+        return;
+    };
+    let Ok(mut answer) = webrtcsignalling::create_trickle_answer(*offer, addr.to_string(), public_ip).await else {
+        let _ = send(&mut socket, SignalOut::Malformed("The provided WebRTC offer is unusable.".into())).await;
+        return;
+    };
+    if send(&mut socket, SignalOut::Description(Box::new(answer.description.clone()))).await.is_err() { return; }
+    let mut gathering = true;
+    loop{ tokio::select!{
+        candidate = answer.candidates.recv(), if gathering => match candidate{
+            Some(candidate) => {
+                gathering = candidate.is_some();
+                if send(&mut socket, SignalOut::Candidate(candidate)).await.is_err() { break; }
+            }
+            None => gathering = false,
+        },
+        msg = socket.recv() => match msg{
+            Some(Ok(Message::Text(text))) => match serde_json::from_str::<SignalIn>(&text) {
+                Ok(SignalIn::Candidate(Some(candidate))) => {
+                    if answer.add_candidate(candidate).await.is_err() { warn!("Unusable candidate from {}", addr); }
+                }
+                Ok(SignalIn::Candidate(None)) => {}
+                _ => {
+                    let _ = send(&mut socket, SignalOut::Malformed("Expected a candidate.".into())).await;
+                    break;
+                }
+            },
+            Some(Ok(_)) => {} // Pings and the like
+            Some(Err(_)) | None => break, // The client went away. The connection may still come up without it.
+        },
+        _ = &mut answer.finished => break,
+    }}
+    let _ = socket.close().await;
+}
+
+#[allow(dead_code)]
 async fn disable_browser_cache<R>(mut r: Response<R>) -> Response<R>{
     let headers = r.headers_mut();
     headers.insert(
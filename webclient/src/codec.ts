@@ -0,0 +1,161 @@
+// Packet encoding/decoding primitives.
+// The packets themselves are generated into packets.ts from the server's definitions.
+
+// Encoding
+// ---------------
+
+// NOTE: Resiable ArrayBuffer is not avaliable enough to warrant using it in this code.
+// Nor is there an appopriate substitution for it at this time in the transpiler stages.
+// Safari iOS <= 16.3 is the big compatibility breaker. https://caniuse.com/mdn-javascript_builtins_arraybuffer_resize
+export class PacketEncoder{
+    private buf = new ArrayBuffer(64);
+    private idx = 0;
+
+    constructor(){}
+    // Makes sure adding `amount` bytes to the buffer won't overflow.
+    private reserve_extra(amount: number){
+        let curcap = this.buf.byteLength;
+        let next = amount + this.idx;
+        if(next > curcap){ // Copy into new buffer
+            let newcap = next + Math.min(1024, next); // Double or add 1kb
+            let newbuf = new ArrayBuffer(newcap);
+            new Uint8Array(newbuf).set(new Uint8Array(this.buf));
+            this.buf = newbuf;
+        }
+    }
+    // Creates a view starting from the current position in the writer
+    private view(){
+        return new Uint8Array(this.buf, this.idx)
+    }
+    // Get a view over the packet that's the correct size for sending
+    public finish(){
+        return new DataView(this.buf, 0, this.idx)
+    }
+
+    // Wraps
+    public append_u8(n: number){
+        this.reserve_extra(1);
+        this.view()[0] = n;
+        this.idx += 1;
+    }
+    public append_bytes(bytes: Uint8Array){
+        this.reserve_extra(bytes.length);
+        this.view().set(bytes);
+        this.idx += bytes.length;
+    }
+    // Truncates larger numbers than u28
+    public append_uvarint(num: number){
+        this.reserve_extra(4);
+        let view = this.view();
+        for(let i = 0; i < 4; i++){
+            view[i] = num & 0x7f;
+            num >>= 7;
+            if(num !== 0){ view[i] |= 0x80 }
+            this.idx += 1;
+            if(num === 0){ break }
+        }
+    }
+    public append_str(dat: string){
+        let msg = new TextEncoder().encode(dat);
+        this.append_uvarint(msg.length);
+        this.reserve_extra(msg.length);
+        this.view().set(msg);
+        this.idx += msg.length;
+    }
+    public append_exhaustive_str(dat: string){
+        let msg = new TextEncoder().encode(dat);
+        this.reserve_extra(msg.byteLength);
+        this.view().set(msg);
+        this.idx += msg.byteLength;
+    }
+    // Numbers past 2^53 have already lost precision. Written as two halves to avoid BigInt.
+    public append_u64(num: number){
+        this.reserve_extra(8);
+        let view = new DataView(this.buf, this.idx);
+        view.setUint32(0, num % 2**32, true);
+        view.setUint32(4, Math.floor(num / 2**32), true);
+        this.idx += 8;
+    }
+    public append_sessionid(sid: Uint8Array){
+        this.append_bytes(sid);
+    }
+    // Writes the uvarint length, then calls the provided function for each element
+    public append_arr<T>(arr: T[], writer: (e: PacketEncoder, x: T)=>void){
+        this.append_uvarint(arr.length);
+        arr.forEach(x=>writer(this, x));
+    }
+}
+
+// Decoding
+// ---------------
+
+export class PktDecoder{
+    private view: DataView;
+    private ofs: number;
+
+    constructor(buffer: ArrayBuffer){
+        this.view = new DataView(buffer)
+        this.ofs = 0;
+    }
+
+    public get_u8(): number {
+        const value = this.view.getUint8(this.ofs);
+        this.ofs += 1;
+        return value;
+    }
+    public get_bytes(count: number): Uint8Array{
+        let value = new Uint8Array(this.view.buffer, this.ofs, count);
+        this.ofs += count;
+        return value;
+    }
+
+    public get_uvarint(): number {
+        let shift = 0;
+        let val = 0;
+        let n = 0;
+        while(true){
+            n++;
+            let byte = this.get_u8();
+            val += (byte & 0x7f) << shift;
+            shift += 7;
+            if((byte & 0x80) === 0 || n === 4){ break; }
+        }
+        return val;
+    }
+    public get_str_len(len: number): string{
+        const stringBytes = new Uint8Array(this.view.buffer, this.ofs, len);
+        this.ofs += len;
+        return new TextDecoder('utf-8').decode(stringBytes);
+    }
+    public get_str(){
+        let length = this.get_uvarint();
+        return this.get_str_len(length);
+    }
+    public get_str_exhaustive(){
+        return this.get_str_len(this.remaining());
+    }
+    // Calls the provided function N times as determined by the next uvarint length
+    public get_arr<T>(reader: (d: PktDecoder)=>T): T[]{
+        let length = this.get_uvarint();
+        return Array.from({length: length}, ()=>reader(this));
+    }
+
+    // Loses precision past 2^53
+    public get_u64(): number{
+        const lo = this.view.getUint32(this.ofs, true);
+        const hi = this.view.getUint32(this.ofs + 4, true);
+        this.ofs += 8;
+        return hi * 2**32 + lo;
+    }
+    public get_sessionid(): Uint8Array{
+        return this.get_bytes(8);
+    }
+
+    public get offset() {
+        return this.ofs;
+    }
+    // 0 = exhausted
+    public remaining(){
+        return this.view.byteLength - this.ofs;
+    }
+}
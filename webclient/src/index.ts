@@ -19,6 +19,14 @@ document.addEventListener('DOMContentLoaded', (_) => {
         }
     };
     document.getElementById('usernameSubmit')!.onclick = ()=>submitNameChange();
+    document.getElementById('historyButton')!.onclick = ()=>sess?.fetch_older_history();
+    document.getElementById('roomBox')!.onkeydown = (e)=>{
+        if (e.key === "Enter"){
+            e.preventDefault();
+            submitRoomChange();
+        }
+    };
+    document.getElementById('roomSubmit')!.onclick = ()=>submitRoomChange();
 
     let presser = document.getElementById('roundButton')!;
     presser.addEventListener('mousedown'  , () => buttonpressed = true );
@@ -34,50 +42,110 @@ window.addEventListener('beforeunload', (_)=>{
 })
 
 let sess: Session | undefined;
+// Kept across reconnects so the server can hand our session back (same name, no leave/join spam)
+let resume_token: Uint8Array | null = null;
+const RECONNECT_DELAY_MS = 2000;
+const HISTORY_PAGE = 50;
 
 async function main(){
     sess = new Session();
-    await sess.connect();
+    try{
+        await sess.connect();
+    }catch(e){
+        if(e instanceof webrtc.RefusedError){
+            addNoteToLog(`You are banned from this server: ${e.message}`);
+            setConnectionStatusText("Banned");
+            sess.shutdown_quietly();
+            return;
+        }
+        console.log(`Connection attempt failed: ${e}`);
+        sess.reconnect_later();
+    }
 }
 
 class Session{
     private conn: webrtc.WebRTCConnection;
     private username: string;
-    private users: string[];
+    // Everyone in the room, by hex session id
+    private participants: Map<string, packet.Participant>;
     private sessionid: Uint8Array|null;
+    private caps: number;
     private periodic_pinger: number|undefined;
+    // Log number of the oldest message we have, for fetching further back
+    private oldest_message: number|undefined;
+    private room: string;
+    // The last chat message sent, in case the server refuses it
+    private last_message: string;
 
     constructor(){
         this.conn = new webrtc.WebRTCConnection(this.on_connection_state_change, this.recv_packet);
         this.username = "";
-        this.users = [];
+        this.participants = new Map();
         this.sessionid = null;
+        this.caps = 0;
         this.periodic_pinger = undefined;
+        this.oldest_message = undefined;
+        this.room = "";
+        this.last_message = "";
     }
 
     public async connect(){
         await this.conn.connect()
-        // send Hello
-        this.conn.send(packet.encode_C2S_Hello(null));
+        // send Hello, offering everything we support
+        this.conn.send(packet.encode_C2S_Hello(packet.PROTOCOL_VERSION, packet.Capabilities.Buttons | packet.Capabilities.History | packet.Capabilities.Rooms, resume_token));
+    }
+    // Drops this connection and starts over, resuming the session if the server still has it
+    public reconnect_later(){
+        if(sess !== this) return; // Already replaced or shut down
+        clearInterval(this.periodic_pinger);
+        this.conn.disconnect();
+        sess = undefined;
+        setTimeout(main, RECONNECT_DELAY_MS);
     }
     // Destroy the connection and the session on page close
     public shutdown(){
-        clearInterval(this.periodic_pinger);
         this.conn.send(packet.encode_C2S_Goodbye());
+        this.shutdown_quietly();
+    }
+    // Drops the connection without reconnecting
+    public shutdown_quietly(){
+        clearInterval(this.periodic_pinger);
         this.conn.disconnect();
         sess = undefined;
     }
 
     public send_message(message: string){
+        // Handled here so the passphrase doesn't end up in the chat
+        if(message.startsWith("/host ")){
+            this.conn.send(packet.encode_C2S_ClaimHost(message.slice("/host ".length)));
+            return;
+        }
+        this.last_message = message;
         this.conn.send(packet.encode_C2S_SendMsg(message));
     }
     public send_name_change(name: string){
         this.conn.send(packet.encode_C2S_SetName(name));
     }
+    public fetch_older_history(){
+        if(this.oldest_message === undefined) return;
+        this.conn.send(packet.encode_C2S_FetchHistory(this.oldest_message, HISTORY_PAGE));
+    }
+    // Creates the room if it doesn't exist
+    public send_room_change(name: string){
+        this.conn.send(packet.encode_C2S_CreateRoom(name));
+    }
+    public join_room(name: string){
+        this.conn.send(packet.encode_C2S_JoinRoom(name));
+    }
     public on_connection_established = ()=>{
-        this.periodic_pinger = setInterval(() => {
-            this.conn.send_unreliable(packet.encode_C2S_Buttons(buttonpressed))
-        }, 100); // 100ms
+        if(this.caps & packet.Capabilities.Buttons){
+            this.periodic_pinger = setInterval(() => {
+                this.conn.send_unreliable(packet.encode_C2S_Buttons(buttonpressed))
+            }, 100); // 100ms
+        }
+        if(this.caps & packet.Capabilities.Rooms){
+            this.conn.send(packet.encode_C2S_ListRooms());
+        }
     }
 
     // Arrow function inherits this, but regular function does not. WHAT
@@ -91,23 +159,74 @@ class Session{
         let pkt = _pkt as (packet.PacketS2C & any);
         if(pkt.id === packet.PktS2Cid.HelloReply){
             this.sessionid = pkt.sid;
+            resume_token = pkt.token.slice(); // Copy out of the packet buffer
+            this.caps = pkt.caps;
             this.set_username(pkt.username)
             this.on_connection_established()
+        }else if(pkt.id === packet.PktS2Cid.HelloReject){
+            addNoteToLog(`Server refused the connection: ${pkt.reason}`);
+            setConnectionStatusText("Incompatible");
+            this.conn.disconnect();
+        }else if(pkt.id === packet.PktS2Cid.ServerClosing){
+            addNoteToLog(`Server closed: ${pkt.reason}`);
+            setConnectionStatusText("Server closed");
+            // Keep trying in case it's only restarting
+            this.reconnect_later();
+        }else if(pkt.id === packet.PktS2Cid.Kicked){
+            addNoteToLog(`You were removed from the server: ${pkt.reason}`);
+            setConnectionStatusText("Kicked");
+            this.shutdown_quietly();
+        }else if(pkt.id === packet.PktS2Cid.Notice){
+            addNoteToLog(pkt.text);
+            // Give the message back so it can be shortened or sent again later
+            if(pkt.kind === packet.NoticeKind.TooLong || pkt.kind === packet.NoticeKind.RateLimited || pkt.kind === packet.NoticeKind.SlowMode){
+                restoreInput(this.last_message);
+            }
         }else if(pkt.id === packet.PktS2Cid.ReceiveMsg){
-            addToLog(pkt.msg);
+            addToLog(pkt.msg, this.sessionid);
+        }else if(pkt.id === packet.PktS2Cid.Whisper){
+            const to = this.participants.get(hex(pkt.to))?.name ?? "someone who left";
+            addWhisperToLog(pkt.msg, to, this.sessionid);
+        }else if(pkt.id === packet.PktS2Cid.ChatHistory){
+            // Only ever older than what we have
+            if(this.oldest_message !== undefined && pkt.first >= this.oldest_message) return;
+            this.oldest_message = pkt.first;
+            prependToLog(pkt.msgs, this.sessionid);
+            setHistoryButtonVisible(pkt.more);
+        }else if(pkt.id === packet.PktS2Cid.RoomJoined){
+            // Also sent when resuming, where we keep what we have
+            if(pkt.name !== this.room){
+                this.room = pkt.name;
+                this.oldest_message = undefined;
+                clearLog();
+                setHistoryButtonVisible(false);
+                setRoomText(pkt.name);
+                this.conn.send(packet.encode_C2S_ListRooms());
+            }
+        }else if(pkt.id === packet.PktS2Cid.RoomList){
+            setRoomList(pkt.rooms);
         }else if(pkt.id === packet.PktS2Cid.SetNameReply){
-            this.set_username(pkt.username);
-        }else if(pkt.id === packet.PktS2Cid.LobbyInfo){
-            // Just makes sure we don't update the display for no reason.
-            if( arrayEqual(pkt.users, this.users) == false ){
-                this.users = pkt.users;
-                setLobbyText(pkt.users);
+            if(pkt.result !== packet.NameResult.Accepted){
+                addNoteToLog(`Couldn't change your name: ${NAME_RESULT_TEXT[pkt.result as packet.NameResult]}`);
             }
+            this.set_username(pkt.name);
+        }else if(pkt.id === packet.PktS2Cid.LobbyInfo){
+            this.participants = new Map(pkt.users.map((x: packet.Participant)=>[hex(x.sid), x]));
+            setLobbyTable(this.participants);
+        }else if(pkt.id === packet.PktS2Cid.ParticipantJoined || pkt.id === packet.PktS2Cid.ParticipantUpdated){
+            this.participants.set(hex(pkt.who.sid), pkt.who);
+            setLobbyTable(this.participants);
+        }else if(pkt.id === packet.PktS2Cid.ParticipantLeft){
+            this.participants.delete(hex(pkt.sid));
+            setLobbyTable(this.participants);
         }
     }
 
     private on_connection_state_change = (state: webrtc.ConnectionState)=>{
         setConnectionStatusText(webrtc.ConnectionState[state])
+        if(state === webrtc.ConnectionState.Closed_Drop){
+            this.reconnect_later();
+        }
     }
     private set_username(username: string){
         this.username = username;
@@ -124,23 +243,67 @@ function setUsernameText(username: string){
     if(box == null) return;
     (box as HTMLInputElement).value = username;
 }
-function setLobbyText(users: string[]){
+const NAME_RESULT_TEXT: {[result in packet.NameResult]: string} = {
+    [packet.NameResult.Accepted]: "",
+    [packet.NameResult.Empty]: "names can't be blank.",
+    [packet.NameResult.TooLong]: "that name is too long.",
+    [packet.NameResult.Reserved]: "that name is reserved.",
+    [packet.NameResult.Taken]: "someone else has that name, or one that looks like it.",
+    [packet.NameResult.Banned]: "that name is banned.",
+    [packet.NameResult.Muted]: "you are muted.",
+    [packet.NameResult.NotAllowed]: "spectators can't change their name.",
+    [packet.NameResult.RateLimited]: "you're doing that too quickly. Wait a moment.",
+};
+const LINK_TEXT: {[link in packet.Link]: string} = {
+    [packet.Link.Good]: "",
+    [packet.Link.Unstable]: "(unstable)",
+    [packet.Link.Lost]: "(reconnecting…)",
+};
+const ROLE_TEXT: {[role in packet.Role]: string} = {
+    [packet.Role.Spectator]: "spectator",
+    [packet.Role.Member]: "",
+    [packet.Role.Moderator]: "moderator",
+    [packet.Role.Host]: "host",
+};
+function setLobbyTable(participants: Map<string, packet.Participant>){
     const table = document.getElementById("lobby");
     if(table == null) return;
     const tablebody = table.querySelector("tbody");
     tablebody?.remove();
     let rows: Node[] = [];
-    users.forEach(s=>{
-        const row = document.createElement('tr')
-        const cell = document.createElement('td');
-        cell.textContent = s;
-        row.appendChild(cell);
+    participants.forEach(p=>{
+        const row = document.createElement('tr');
+        for(const text of [p.hand ? "👋" : "", p.name, ROLE_TEXT[p.role], LINK_TEXT[p.link]]){
+            const cell = document.createElement('td');
+            cell.textContent = text;
+            row.appendChild(cell);
+        }
         rows.push(row);
     });
     const body = document.createElement("tbody");
     body.append(...rows);
     table.append(body);
 }
+function hex(bytes: Uint8Array){
+    return Array.from(bytes, x=>x.toString(16).padStart(2, "0")).join("");
+}
+
+function setRoomText(room: string){
+    document.getElementById("roomName")!.innerText = `${room}:`;
+}
+function setRoomList(rooms: string[]){
+    const list = document.getElementById("roomList");
+    if(list == null) return;
+    list.replaceChildren(...rooms.map(name=>{
+        const item = document.createElement('li');
+        const link = document.createElement('a');
+        link.href = "#";
+        link.textContent = name;
+        link.onclick = (e)=>{ e.preventDefault(); sess?.join_room(name); };
+        item.appendChild(link);
+        return item;
+    }));
+}
 
 function arrayEqual<T>(a: T[], b: T[]){
     if (a === b) return true;
@@ -152,9 +315,61 @@ function arrayEqual<T>(a: T[], b: T[]){
     return true;
 }
 
-function addToLog(msg: string){
-    const displayBox = document.getElementById('displayBox') as HTMLInputElement;
-    displayBox.value += msg + '\n';
+// One line of the chat log. `own` is our session id, to pick out our own messages.
+function renderMessage(msg: packet.ChatMsg, own: Uint8Array|null): HTMLElement{
+    const line = document.createElement('div');
+    const time = document.createElement('span');
+    time.className = "msgTime";
+    time.textContent = new Date(msg.time).toLocaleTimeString([], {hour: '2-digit', minute: '2-digit'}) + " ";
+    line.appendChild(time);
+    const name = document.createElement('span');
+    name.className = "msgName";
+    if(msg.kind === packet.MsgKind.System){
+        line.className = "msgSystem";
+        name.textContent = ">>> ";
+    }else if(msg.kind === packet.MsgKind.Action){
+        line.className = "msgAction";
+        name.textContent = `* ${msg.name} `;
+    }else{
+        name.textContent = `${msg.name}) `;
+    }
+    if(own !== null && arrayEqual(Array.from(msg.author), Array.from(own))){
+        line.classList.add("msgOwn");
+    }
+    line.appendChild(name);
+    line.appendChild(document.createTextNode(msg.text));
+    return line;
+}
+function addToLog(msg: packet.ChatMsg, own: Uint8Array|null){
+    const displayBox = document.getElementById('displayBox')!;
+    displayBox.appendChild(renderMessage(msg, own));
+    displayBox.scrollTop = displayBox.scrollHeight;
+}
+// A direct message, to or from us
+function addWhisperToLog(msg: packet.ChatMsg, to: string, own: Uint8Array|null){
+    const line = renderMessage(msg, own);
+    line.classList.add("msgWhisper");
+    const name = line.querySelector(".msgName")!;
+    name.textContent = line.classList.contains("msgOwn") ? `(to ${to}) ` : `(from ${msg.name}) `;
+    const displayBox = document.getElementById('displayBox')!;
+    displayBox.appendChild(line);
+    displayBox.scrollTop = displayBox.scrollHeight;
+}
+// For the client's own remarks, which aren't chat messages
+function addNoteToLog(text: string){
+    const line = document.createElement('div');
+    line.className = "msgSystem";
+    line.textContent = `>>> ${text}`;
+    document.getElementById('displayBox')!.appendChild(line);
+}
+function clearLog(){
+    document.getElementById('displayBox')!.replaceChildren();
+}
+function prependToLog(msgs: packet.ChatMsg[], own: Uint8Array|null){
+    document.getElementById('displayBox')!.prepend(...msgs.map(x=>renderMessage(x, own)));
+}
+function setHistoryButtonVisible(visible: boolean){
+    document.getElementById('historyButton')!.hidden = !visible;
 }
 
 // Send messages
@@ -163,6 +378,16 @@ function submitMessage() {
     sess?.send_message(inputBox.value);
     inputBox.value = '';
 }
+// Puts a message back in the input box, unless something else has been typed since
+function restoreInput(message: string){
+    const inputBox = document.getElementById('inputBox')! as HTMLInputElement;
+    if(inputBox.value === '') inputBox.value = message;
+}
+function submitRoomChange(){
+    const inputBox = document.getElementById('roomBox')! as HTMLInputElement;
+    sess?.send_room_change(inputBox.value);
+    inputBox.value = '';
+}
 function submitNameChange(){
     const inputBox = document.getElementById('usernameBox')! as HTMLInputElement;
     sess?.send_name_change(inputBox.value);
@@ -1,93 +1,133 @@
+// GENERATED FILE: run `cargo run --bin gen-packets` after changing src/packets.rs
+import { PacketEncoder, PktDecoder } from "./codec"
+
+export const PROTOCOL_VERSION = 7;
+export enum Capabilities{
+    Buttons = 1,
+    History = 2,
+    Rooms = 4,
+}
+export enum MsgKind{
+    User = 0,
+    System = 1,
+    Action = 2,
+}
+export enum NameResult{
+    Accepted = 0,
+    Empty = 1,
+    TooLong = 2,
+    Reserved = 3,
+    Taken = 4,
+    Banned = 5,
+    Muted = 6,
+    NotAllowed = 7,
+    RateLimited = 8,
+}
+export enum Link{
+    Good = 0,
+    Unstable = 1,
+    Lost = 2,
+}
+export enum Role{
+    Spectator = 0,
+    Member = 1,
+    Moderator = 2,
+    Host = 3,
+}
+export enum NoticeKind{
+    TooLong = 0,
+    RateLimited = 1,
+    SlowMode = 2,
+    Repeated = 3,
+    Muted = 4,
+    NotAllowed = 5,
+    NoRecipient = 6,
+    Command = 7,
+}
+
+// Records
+// ---------------
+
+export type ChatMsg = {
+    id: number,
+    time: number,
+    author: Uint8Array,
+    kind: MsgKind,
+    name: string,
+    text: string,
+}
+export function encode_ChatMsg(enc: PacketEncoder, x: ChatMsg){
+    enc.append_u64(x.id);
+    enc.append_u64(x.time);
+    enc.append_sessionid(x.author);
+    enc.append_u8(x.kind);
+    enc.append_str(x.name);
+    enc.append_str(x.text);
+}
+export function decode_ChatMsg(d: PktDecoder): ChatMsg{
+    return {
+        id: d.get_u64(),
+        time: d.get_u64(),
+        author: d.get_sessionid(),
+        kind: d.get_u8(),
+        name: d.get_str(),
+        text: d.get_str(),
+    };
+}
+
+export type Participant = {
+    sid: Uint8Array,
+    name: string,
+    hand: boolean,
+    link: Link,
+    role: Role,
+}
+export function encode_Participant(enc: PacketEncoder, x: Participant){
+    enc.append_sessionid(x.sid);
+    enc.append_str(x.name);
+    enc.append_u8(+x.hand);
+    enc.append_u8(x.link);
+    enc.append_u8(x.role);
+}
+export function decode_Participant(d: PktDecoder): Participant{
+    return {
+        sid: d.get_sessionid(),
+        name: d.get_str(),
+        hand: d.get_u8() !== 0,
+        link: d.get_u8(),
+        role: d.get_u8(),
+    };
+}
+
 // Encoding
 // ---------------
-enum PktC2Sid{
+export enum PktC2Sid{
     Hello = 0,
     SendMsg = 1,
     SetName = 2,
     Goodbye = 3,
     Buttons = 4,
+    FetchHistory = 5,
+    ListRooms = 6,
+    JoinRoom = 7,
+    CreateRoom = 8,
+    ClaimHost = 9,
+    Whisper = 10,
 }
 
-// NOTE: Resiable ArrayBuffer is not avaliable enough to warrant using it in this code.
-// Nor is there an appopriate substitution for it at this time in the transpiler stages.
-// Safari iOS <= 16.3 is the big compatibility breaker. https://caniuse.com/mdn-javascript_builtins_arraybuffer_resize
-class PacketEncoder{
-    private buf = new ArrayBuffer(64);
-    private idx = 0;
-
-    constructor(){}
-    // Makes sure adding `amount` bytes to the buffer won't overflow.
-    private reserve_extra(amount: number){
-        let curcap = this.buf.byteLength;
-        let next = amount + this.idx;
-        if(next > curcap){ // Copy into new buffer
-            let newcap = next + Math.min(1024, next); // Double or add 1kb
-            let newbuf = new ArrayBuffer(newcap);
-            new Uint8Array(newbuf).set(new Uint8Array(this.buf));
-            this.buf = newbuf;
-        }
-    }
-    // Creates a view starting from the current position in the writer
-    private view(){
-        return new Uint8Array(this.buf, this.idx)
-    }
-    // Get a view over the packet that's the correct size for sending
-    public finish(){
-        return new DataView(this.buf, 0, this.idx)
-    }
-
-    // Wraps
-    public append_u8(n: number){
-        this.reserve_extra(1);
-        this.view()[0] = n;
-        this.idx += 1;
-    }
-    public append_bytes(bytes: Uint8Array){
-        this.reserve_extra(bytes.length);
-        this.view().set(bytes);
-        this.idx += bytes.length;
-    }
-    // Truncates larger numbers than u28
-    public append_uvarint(num: number){
-        this.reserve_extra(4);
-        let view = this.view();
-        let n = 0;
-        for(let i = 0; i < 4; i++){
-            view[i] = num & 0x7f;
-            num >>= 7;
-            if(num !== 0){ view[i] |= 0x80 }
-            this.idx += 1;
-            if(num === 0){ break }
-        }
-    }
-    public append_str(dat: string){
-        let msg = new TextEncoder().encode(dat);
-        this.append_uvarint(msg.length);
-        this.reserve_extra(msg.length);
-        this.view().set(msg);
-        this.idx += msg.length;
-    }
-    public append_exhaustive_str(dat: string){
-        let msg = new TextEncoder().encode(dat);
-        this.reserve_extra(msg.byteLength);
-        this.view().set(msg);
-        this.idx += msg.byteLength;
-    }
-}
-
-export function encode_C2S_Hello(cached_sid: Uint8Array | null){
+export function encode_C2S_Hello(version: number, caps: number, resume: Uint8Array | null){
     let enc = new PacketEncoder();
     enc.append_u8(PktC2Sid.Hello);
-    if(cached_sid !== null){
-        enc.append_bytes(cached_sid);
-    }
+    enc.append_uvarint(version);
+    enc.append_uvarint(caps);
+    if(resume !== null){ enc.append_bytes(resume); }
     return enc.finish();
 }
 
-export function encode_C2S_SendMsg(message: string){
+export function encode_C2S_SendMsg(msg: string){
     let enc = new PacketEncoder();
     enc.append_u8(PktC2Sid.SendMsg);
-    enc.append_exhaustive_str(message);
+    enc.append_exhaustive_str(msg);
     return enc.finish();
 }
 
@@ -111,98 +151,160 @@ export function encode_C2S_Buttons(pressed: boolean){
     return enc.finish();
 }
 
+export function encode_C2S_FetchHistory(before: number, count: number){
+    let enc = new PacketEncoder();
+    enc.append_u8(PktC2Sid.FetchHistory);
+    enc.append_u64(before);
+    enc.append_uvarint(count);
+    return enc.finish();
+}
+
+export function encode_C2S_ListRooms(){
+    let enc = new PacketEncoder();
+    enc.append_u8(PktC2Sid.ListRooms);
+    return enc.finish();
+}
+
+export function encode_C2S_JoinRoom(name: string){
+    let enc = new PacketEncoder();
+    enc.append_u8(PktC2Sid.JoinRoom);
+    enc.append_exhaustive_str(name);
+    return enc.finish();
+}
+
+export function encode_C2S_CreateRoom(name: string){
+    let enc = new PacketEncoder();
+    enc.append_u8(PktC2Sid.CreateRoom);
+    enc.append_exhaustive_str(name);
+    return enc.finish();
+}
+
+export function encode_C2S_ClaimHost(passphrase: string){
+    let enc = new PacketEncoder();
+    enc.append_u8(PktC2Sid.ClaimHost);
+    enc.append_exhaustive_str(passphrase);
+    return enc.finish();
+}
+
+export function encode_C2S_Whisper(target: Uint8Array, msg: string){
+    let enc = new PacketEncoder();
+    enc.append_u8(PktC2Sid.Whisper);
+    enc.append_sessionid(target);
+    enc.append_exhaustive_str(msg);
+    return enc.finish();
+}
+
 // Decoding
 // ---------------
-
 export enum PktS2Cid{
     HelloReply = 0,
     ReceiveMsg = 1,
     SetNameReply = 2,
     LobbyInfo = 3,
+    HelloReject = 4,
+    ChatHistory = 5,
+    RoomList = 6,
+    RoomJoined = 7,
+    ParticipantJoined = 8,
+    ParticipantUpdated = 9,
+    ParticipantLeft = 10,
+    ServerClosing = 11,
+    Kicked = 12,
+    Notice = 13,
+    Whisper = 14,
 }
 
-export interface PacketS2C{
-    id: PktS2Cid
-}
-
-type PktS2C_HelloReply = PktS2C_SetNameReply & {
+export type PktS2C_HelloReply = {
     sid: Uint8Array,
+    token: Uint8Array,
+    version: number,
+    caps: number,
     username: string,
 }
-type PktS2C_ReceiveMsg = {
-    msg: string,
+
+export type PktS2C_ReceiveMsg = {
+    msg: ChatMsg,
 }
-type PktS2C_SetNameReply = {
-    username: string,
+
+export type PktS2C_SetNameReply = {
+    result: NameResult,
+    name: string,
 }
-type PktS2C_LobbyInfo = {
-    users: string[],
+
+export type PktS2C_LobbyInfo = {
+    users: Participant[],
 }
 
-export enum ParseError{
-    Unimplemented,
-    UnknownPacket
+export type PktS2C_HelloReject = {
+    version: number,
+    reason: string,
 }
 
-class PktDecoder{
-    private view: DataView;
-    private ofs: number;
+export type PktS2C_ChatHistory = {
+    first: number,
+    more: boolean,
+    msgs: ChatMsg[],
+}
 
-    constructor(buffer: ArrayBuffer){
-        this.view = new DataView(buffer)
-        this.ofs = 0;
-    }
+export type PktS2C_RoomList = {
+    rooms: string[],
+}
 
-    public get_u8(): number {
-        const value = this.view.getUint8(this.ofs);
-        this.ofs += 1;
-        return value;
-    }
-    public get_bytes(count: number): Uint8Array{
-        let value = new Uint8Array(this.view.buffer, this.ofs, count);
-        this.ofs += count;
-        return value;
-    }
+export type PktS2C_RoomJoined = {
+    name: string,
+}
 
-    public get_uvarint(): number {
-        let shift = 0;
-        let val = 0;
-        let n = 0;
-        while(true){
-            n++;
-            let byte = this.get_u8();
-            val += (byte & 0x7f) << shift;
-            shift += 7;
-            if((byte & 0x80) === 0 || n === 4){ break; }
-        }
-        return val;
-    }
-    public get_str_len(len: number): string{
-        const stringBytes = new Uint8Array(this.view.buffer, this.ofs, len);
-        this.ofs += len;
-        return new TextDecoder('utf-8').decode(stringBytes);
-    }
-    public get_str(){
-        let length = this.get_uvarint();
-        return this.get_str_len(length);
-    }
-    public get_str_exhaustive(){
-        let length = this.view.byteLength - this.offset;
-        return this.get_str_len(length);
-    }
-    // Calls the provided function N times as determined by the next uvarint length
-    public get_arr<T>(reader: (d: PktDecoder)=>T): T[]{
-        let length = this.get_uvarint();
-        return Array.from({length: length}, ()=>reader(this));
-    }
+export type PktS2C_ParticipantJoined = {
+    who: Participant,
+}
 
-    public get_sessionid(): Uint8Array{
-        return this.get_bytes(8);
-    }
+export type PktS2C_ParticipantUpdated = {
+    who: Participant,
+}
 
-    public get offset() {
-        return this.ofs;
-    }
+export type PktS2C_ParticipantLeft = {
+    sid: Uint8Array,
+}
+
+export type PktS2C_ServerClosing = {
+    reason: string,
+}
+
+export type PktS2C_Kicked = {
+    reason: string,
+}
+
+export type PktS2C_Notice = {
+    kind: NoticeKind,
+    text: string,
+}
+
+export type PktS2C_Whisper = {
+    to: Uint8Array,
+    msg: ChatMsg,
+}
+
+export type PacketS2C =
+    | {id: PktS2Cid.HelloReply} & PktS2C_HelloReply
+    | {id: PktS2Cid.ReceiveMsg} & PktS2C_ReceiveMsg
+    | {id: PktS2Cid.SetNameReply} & PktS2C_SetNameReply
+    | {id: PktS2Cid.LobbyInfo} & PktS2C_LobbyInfo
+    | {id: PktS2Cid.HelloReject} & PktS2C_HelloReject
+    | {id: PktS2Cid.ChatHistory} & PktS2C_ChatHistory
+    | {id: PktS2Cid.RoomList} & PktS2C_RoomList
+    | {id: PktS2Cid.RoomJoined} & PktS2C_RoomJoined
+    | {id: PktS2Cid.ParticipantJoined} & PktS2C_ParticipantJoined
+    | {id: PktS2Cid.ParticipantUpdated} & PktS2C_ParticipantUpdated
+    | {id: PktS2Cid.ParticipantLeft} & PktS2C_ParticipantLeft
+    | {id: PktS2Cid.ServerClosing} & PktS2C_ServerClosing
+    | {id: PktS2Cid.Kicked} & PktS2C_Kicked
+    | {id: PktS2Cid.Notice} & PktS2C_Notice
+    | {id: PktS2Cid.Whisper} & PktS2C_Whisper
+;
+
+export enum ParseError{
+    Unimplemented,
+    UnknownPacket
 }
 
 type DecoderResult<T> = T | ParseError;
@@ -225,31 +327,104 @@ export function decode_packet(buffer: ArrayBuffer): PacketS2C | ParseError{
     }
 }
 
-let decode_unimplemented: DecoderFunction = (_)=>ParseError.Unimplemented;
-
 let decode_S2C_HelloReply: DecoderFunction<PktS2C_HelloReply> = (d)=>{
     return {
         sid: d.get_sessionid(),
+        token: d.get_bytes(16),
+        version: d.get_uvarint(),
+        caps: d.get_uvarint(),
         username: d.get_str_exhaustive(),
     };
 }
+
 let decode_S2C_ReceiveMsg: DecoderFunction<PktS2C_ReceiveMsg> = (d)=>{
     return {
-        msg: d.get_str(),
-    }
+        msg: decode_ChatMsg(d),
+    };
 }
+
 let decode_S2C_SetNameReply: DecoderFunction<PktS2C_SetNameReply> = (d)=>{
     return {
-        username: d.get_str(),
-    }
+        result: d.get_u8(),
+        name: d.get_str(),
+    };
 }
+
 let decode_S2C_LobbyInfo: DecoderFunction<PktS2C_LobbyInfo> = (d)=>{
     return {
-        users: d.get_arr((d)=>{
-            // console.log(`${JSON.stringify(d)}`);
-            return d.get_str();
-        }),
-    }
+        users: d.get_arr((d)=>decode_Participant(d)),
+    };
+}
+
+let decode_S2C_HelloReject: DecoderFunction<PktS2C_HelloReject> = (d)=>{
+    return {
+        version: d.get_uvarint(),
+        reason: d.get_str_exhaustive(),
+    };
+}
+
+let decode_S2C_ChatHistory: DecoderFunction<PktS2C_ChatHistory> = (d)=>{
+    return {
+        first: d.get_u64(),
+        more: d.get_u8() !== 0,
+        msgs: d.get_arr((d)=>decode_ChatMsg(d)),
+    };
+}
+
+let decode_S2C_RoomList: DecoderFunction<PktS2C_RoomList> = (d)=>{
+    return {
+        rooms: d.get_arr((d)=>d.get_str()),
+    };
+}
+
+let decode_S2C_RoomJoined: DecoderFunction<PktS2C_RoomJoined> = (d)=>{
+    return {
+        name: d.get_str_exhaustive(),
+    };
+}
+
+let decode_S2C_ParticipantJoined: DecoderFunction<PktS2C_ParticipantJoined> = (d)=>{
+    return {
+        who: decode_Participant(d),
+    };
+}
+
+let decode_S2C_ParticipantUpdated: DecoderFunction<PktS2C_ParticipantUpdated> = (d)=>{
+    return {
+        who: decode_Participant(d),
+    };
+}
+
+let decode_S2C_ParticipantLeft: DecoderFunction<PktS2C_ParticipantLeft> = (d)=>{
+    return {
+        sid: d.get_sessionid(),
+    };
+}
+
+let decode_S2C_ServerClosing: DecoderFunction<PktS2C_ServerClosing> = (d)=>{
+    return {
+        reason: d.get_str_exhaustive(),
+    };
+}
+
+let decode_S2C_Kicked: DecoderFunction<PktS2C_Kicked> = (d)=>{
+    return {
+        reason: d.get_str_exhaustive(),
+    };
+}
+
+let decode_S2C_Notice: DecoderFunction<PktS2C_Notice> = (d)=>{
+    return {
+        kind: d.get_u8(),
+        text: d.get_str_exhaustive(),
+    };
+}
+
+let decode_S2C_Whisper: DecoderFunction<PktS2C_Whisper> = (d)=>{
+    return {
+        to: d.get_sessionid(),
+        msg: decode_ChatMsg(d),
+    };
 }
 
 // "Lookup table" that decodes incoming packets into legible types.
@@ -258,4 +433,15 @@ const PktDecodeLookup: { [id in PktS2Cid]: DecoderFunction<any>} = {
     [PktS2Cid.ReceiveMsg]: decode_S2C_ReceiveMsg,
     [PktS2Cid.SetNameReply]: decode_S2C_SetNameReply,
     [PktS2Cid.LobbyInfo]: decode_S2C_LobbyInfo,
-};
\ No newline at end of file
+    [PktS2Cid.HelloReject]: decode_S2C_HelloReject,
+    [PktS2Cid.ChatHistory]: decode_S2C_ChatHistory,
+    [PktS2Cid.RoomList]: decode_S2C_RoomList,
+    [PktS2Cid.RoomJoined]: decode_S2C_RoomJoined,
+    [PktS2Cid.ParticipantJoined]: decode_S2C_ParticipantJoined,
+    [PktS2Cid.ParticipantUpdated]: decode_S2C_ParticipantUpdated,
+    [PktS2Cid.ParticipantLeft]: decode_S2C_ParticipantLeft,
+    [PktS2Cid.ServerClosing]: decode_S2C_ServerClosing,
+    [PktS2Cid.Kicked]: decode_S2C_Kicked,
+    [PktS2Cid.Notice]: decode_S2C_Notice,
+    [PktS2Cid.Whisper]: decode_S2C_Whisper,
+};
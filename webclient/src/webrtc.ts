@@ -7,22 +7,47 @@ export enum ConnectionState{
     Closed_Drop,
 }
 
+// The server won't take a connection from us at all
+export class RefusedError extends Error{}
+// The WebSocket signalling endpoint couldn't be reached, so the POST one should be tried
+class SignallingUnavailable extends Error{}
+
+// The data channels the server takes, and how each delivers what's sent over it. Must match the server's (webrtcpeer.rs).
+// Required ones are opened with the connection, and it can't do without them. Others can be opened at any time with `open_channel`.
+export const CHANNELS: {[label: string]: {init: RTCDataChannelInit, required: boolean}} = {
+    ro: {init: {}, required: true}, // Reliable, ordered
+    uu: {init: {maxRetransmits: 0, ordered: false}, required: true}, // Unreliable, unordered
+};
+
 // Transport layer wrapper around webrtc.
 // Creating this class causes a connection attempt that will resolve in the future.
 export class WebRTCConnection{
     private peer: RTCPeerConnection;
-    private chanr: RTCDataChannel;
-    private chanu: RTCDataChannel;
+    // Open (or opening) channels by label
+    private channels: Map<string, RTCDataChannel>;
+    private cb_recv: (data: ArrayBuffer, label: string)=>void;
+    // Trickle ICE signalling, while it's open
+    private signal: WebSocket|undefined;
+    // Our candidates, gathered before there was anywhere to send them
+    private local_candidates: (RTCIceCandidateInit|null)[];
 
     constructor(
         cb_connection_state: (state: ConnectionState)=>void,
-        cb_recv: (data: ArrayBuffer)=>void,
+        cb_recv: (data: ArrayBuffer, label: string)=>void,
     ){
         this.peer = new RTCPeerConnection();
-        this.chanr = this.create_channel(cb_recv, "ro");
-        this.chanu = this.create_channel(cb_recv, "uu", { // unreliable, unordered
-            maxRetransmits: 0, ordered: false
-        });
+        this.channels = new Map();
+        this.cb_recv = cb_recv;
+        this.signal = undefined;
+        this.local_candidates = [];
+        this.peer.onicecandidate = ({candidate})=>{
+            const init = candidate?.toJSON() ?? null; // null once they're all gathered
+            if(this.signal?.readyState === WebSocket.OPEN) this.signal.send(JSON.stringify({Candidate: init}));
+            else this.local_candidates.push(init);
+        };
+        for(const label in CHANNELS){
+            if(CHANNELS[label].required) this.open_channel(label);
+        }
         this.peer.onconnectionstatechange = ()=>{
             const state = this.peer.connectionState;
             switch (state) {
@@ -42,12 +67,28 @@ export class WebRTCConnection{
         };
     }
 
-    private create_channel(cb_recv: (data: ArrayBuffer)=>void, label: string, dataChannelDict?: RTCDataChannelInit){
-        let channel = this.peer.createDataChannel(label, dataChannelDict);
-        channel.binaryType = "arraybuffer";
-        channel.onclose = ()=>this.peer.close()
-        channel.onmessage = ({data/*ArrayBuffer*/})=>cb_recv(data as ArrayBuffer);
-        return channel;
+    // Opens one of CHANNELS, if it isn't already. Resolves once it can be used.
+    public open_channel(label: string): Promise<void>{
+        const spec = CHANNELS[label];
+        if(spec === undefined) return Promise.reject(new Error(`No such channel: ${label}`));
+        let channel = this.channels.get(label);
+        if(channel === undefined){
+            channel = this.peer.createDataChannel(label, spec.init);
+            channel.binaryType = "arraybuffer";
+            // Losing a required channel loses the connection. Others can just be opened again.
+            channel.addEventListener("close", ()=>{
+                this.channels.delete(label);
+                if(spec.required) this.peer.close();
+            });
+            channel.onmessage = ({data/*ArrayBuffer*/})=>this.cb_recv(data as ArrayBuffer, label);
+            this.channels.set(label, channel);
+        }
+        if(channel.readyState === "open") return Promise.resolve();
+        const opening = channel;
+        return new Promise((resolve, reject)=>{
+            opening.addEventListener("open", ()=>resolve());
+            opening.addEventListener("close", ()=>reject(new Error(`Channel ${label} closed`)));
+        });
     }
 
     // Attempts to connect to the remote. Returns when connected or failed.
@@ -55,25 +96,76 @@ export class WebRTCConnection{
         // Do the connection
         let offer = await this.peer.createOffer();
         this.peer.setLocalDescription(offer);
-        let response = await this.exchange_connection_details(offer);
-        this.peer.setRemoteDescription(response.description);
-        response.candidates.forEach(e => { this.peer.addIceCandidate(e) });
-        // Wait for it to be established (TODO: chanu?)
-        await new Promise<void>((resolve, reject)=>this.chanr.onopen = ()=>resolve())
+        try{
+            await this.trickle_connection_details(offer);
+        }catch(e){
+            if(!(e instanceof SignallingUnavailable)) throw e;
+            // Fall back on swapping everything at once
+            let response = await this.exchange_connection_details(offer);
+            this.peer.setRemoteDescription(response.description);
+            response.candidates.forEach(e => { this.peer.addIceCandidate(e) });
+        }
+        // Wait for it to be established
+        await Promise.all(Array.from(this.channels.keys(), label => this.open_channel(label)));
     }
 
     public disconnect(){
-        this.chanr.close();
-        this.chanu.close();
+        this.signal?.close();
+        this.channels.forEach(channel => channel.close());
         this.peer.close();
     }
 
     // Dataview is apparently safe https://developer.mozilla.org/en-US/docs/Web/API/RTCDataChannel/send
     public send(data: Blob|ArrayBuffer|DataView|TypedArray){
-        this.chanr.send(data as any);
+        this.send_on("ro", data);
     }
     public send_unreliable(data: Blob|ArrayBuffer|DataView|TypedArray){
-        this.chanu.send(data as any);
+        this.send_on("uu", data);
+    }
+    // Sends over a channel opened with `open_channel`
+    public send_on(label: string, data: Blob|ArrayBuffer|DataView|TypedArray){
+        const channel = this.channels.get(label);
+        if(channel === undefined) throw new Error(`Channel ${label} isn't open`);
+        channel.send(data as any);
+    }
+
+    // Sends the offer over a WebSocket, then swaps candidates as each side gathers them. Returns once the answer is in.
+    // The server closes the socket when it's no longer needed.
+    private trickle_connection_details(offer: RTCSessionDescriptionInit): Promise<void>{
+        return new Promise((resolve, reject)=>{
+            const scheme = location.protocol === "https:" ? "wss" : "ws";
+            const signal = new WebSocket(`${scheme}://${location.host}/signal`);
+            let opened = false;
+            let described = false;
+            // Candidates can't be added until the answer has been
+            let answered = Promise.resolve();
+            signal.onopen = ()=>{
+                opened = true;
+                this.signal = signal;
+                signal.send(JSON.stringify({Offer: offer}));
+                this.local_candidates.forEach(c => signal.send(JSON.stringify({Candidate: c})));
+                this.local_candidates = [];
+            };
+            signal.onmessage = ({data})=>{
+                const msg = JSON.parse(data);
+                if(msg.Banned !== undefined){
+                    reject(new RefusedError(msg.Banned));
+                }else if(msg.Malformed !== undefined){
+                    reject(new Error(msg.Malformed));
+                }else if(msg.Description !== undefined){
+                    described = true;
+                    answered = this.peer.setRemoteDescription(msg.Description);
+                    answered.then(()=>resolve(), reject);
+                }else if(msg.Candidate){ // null once the server has gathered them all
+                    answered.then(()=>this.peer.addIceCandidate(msg.Candidate));
+                }
+            };
+            signal.onclose = ()=>{
+                this.signal = undefined;
+                if(!opened) reject(new SignallingUnavailable());
+                else if(!described) reject(new Error("Signalling ended before the server answered"));
+            };
+        });
     }
 
     private async exchange_connection_details(offer: RTCSessionDescriptionInit):Promise<{
@@ -85,6 +177,10 @@ export class WebRTCConnection{
             body: JSON.stringify(offer),
             headers: { "Content-type": "application/json; charset=UTF-8" }
         });
-        return await response.json();
+        let json = await response.json();
+        if(json.Banned !== undefined){
+            throw new RefusedError(json.Banned);
+        }
+        return json;
     }
 }
\ No newline at end of file